@@ -0,0 +1,1084 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::expr::{string_literal, BinOp, Expr};
+use crate::obj::{Place, Reloc, Target};
+use crate::verify::check_proc;
+use crate::vm::{DebugInfo, Instruction, Proc, SourceLoc, Word};
+
+// The result of assembling a .vm source: the program plus, for every
+// instruction, the (1 based) source line and column it came from and the
+// included file it is in, None for the top level source. `data` is the data
+// segment and `data_labels` the labels defined in it, with their offsets.
+// `procs` are the `.proc` blocks, whose stack effects have been checked.
+// `exports`, `imports` and `relocs` only matter for object files, see
+// `assemble_object`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub program: Vec<Instruction>,
+    pub lines: Vec<usize>,
+    pub columns: Vec<usize>,
+    pub files: Vec<Option<String>>,
+    pub labels: Vec<(String, usize)>,
+    pub consts: Vec<(String, Word)>,
+    pub data: Vec<u8>,
+    pub data_labels: Vec<(String, usize)>,
+    pub procs: Vec<Proc>,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+    pub relocs: Vec<Reloc>
+}
+
+impl Assembly {
+    // Index of the first instruction on `line` of `file`, or on the closest
+    // line after it that has one.
+    pub fn instruction_at(&self, file: Option<&str>, line: usize) -> Option<usize> {
+        (0..self.program.len()).find(|i| self.files[*i].as_deref() == file && self.lines[*i] >= line)
+    }
+    pub fn instruction_at_line(&self, line: usize) -> Option<usize> {
+        self.instruction_at(None, line)
+    }
+    // The file instruction `pc` came from, `main` for the top level source.
+    pub fn file<'a>(&'a self, pc: usize, main: &'a str) -> &'a str {
+        self.files[pc].as_deref().unwrap_or(main)
+    }
+    pub fn debug_info(&self, file: &str) -> DebugInfo {
+        DebugInfo {
+            locations: (0..self.program.len())
+                .map(|i| SourceLoc { file: self.file(i, file).to_string(), line: self.lines[i], column: self.columns[i] })
+                .collect(),
+            labels: self.labels.clone()
+        }
+    }
+}
+
+// A line of source after includes and macros are expanded, with the file and
+// line it came from. Expanded macro bodies carry the place of the invocation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: Option<String>,
+    pub line: usize,
+    pub text: String
+}
+
+// `file` is None for errors in the top level source; `included_from` lists
+// the `.include` lines that led to an included file, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub message: String,
+    pub included_from: Vec<(Option<String>, usize)>
+}
+
+impl AsmError {
+    pub fn at(file: &Option<String>, line: usize, message: String) -> AsmError {
+        AsmError { file: file.clone(), line, message, included_from: vec![] }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} at {}:{}", self.message, file, self.line)?,
+            None => write!(f, "{} at line {}", self.message, self.line)?
+        }
+        for (file, line) in &self.included_from {
+            match file {
+                Some(file) => write!(f, ", included from {}:{}", file, line)?,
+                None => write!(f, ", included from line {}", line)?
+            }
+        }
+        write!(f, ".")
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Splits a line into tokens with their (1 based) columns.
+pub(crate) fn tokens(text: &str) -> Vec<(usize, &str)> {
+    let mut ret = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                ret.push((text[..s].chars().count() + 1, &text[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+pub fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Macros may invoke other macros, but not more than this many levels deep.
+const MACRO_DEPTH: usize = 64;
+
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<String>
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+// Length in bytes of the character or string literal at the start of
+// `text`, up to and including the closing quote, or all of `text` if it is
+// not closed.
+fn quoted_len(text: &str) -> usize {
+    let quote = text.chars().next();
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            c if Some(c) == quote && !escaped => return i + 1,
+            _ => escaped = false
+        }
+    }
+    text.len()
+}
+
+// Splits a line into its code and the `;` comment after it, if any. A `;`
+// in a character or string literal does not start a comment.
+pub(crate) fn split_comment(text: &str) -> (&str, Option<&str>) {
+    let mut i = 0;
+    while let Some(start) = text[i..].find([';', '\'', '"']).map(|s| i + s) {
+        if text[start..].starts_with(';') {
+            return (&text[..start], Some(&text[start..]));
+        }
+        i = start + quoted_len(&text[start..]);
+    }
+    (text, None)
+}
+
+// Calls `f` for every run of identifier characters in `text` outside of
+// character and string literals, along with whether it is written as a `\param`
+// reference, and replaces the run with what `f` returns.
+fn map_words<F>(text: &str, mut f: F) -> Result<String, AsmError>
+    where F: FnMut(&str, bool) -> Result<Option<String>, AsmError>
+{
+    let mut ret = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(|c| is_ident_char(c) || c == '\'' || c == '"') {
+        if rest[start..].starts_with(['\'', '"']) {
+            let end = start + quoted_len(&rest[start..]);
+            ret += &rest[..end];
+            rest = &rest[end..];
+            continue;
+        }
+        let end = rest[start..].find(|c| !is_ident_char(c)).map(|e| start + e).unwrap_or(rest.len());
+        let param = rest[..start].ends_with('\\');
+        let word = &rest[start..end];
+        match f(word, param)? {
+            Some(replacement) => {
+                ret += &rest[..start - param as usize];
+                ret += &replacement;
+            }
+            None => ret += &rest[..end]
+        }
+        rest = &rest[end..];
+    }
+    ret += rest;
+    Ok(ret)
+}
+
+// Splits macro arguments at commas or whitespace outside of parentheses and
+// character or string literals.
+pub(crate) fn split_args(text: &str) -> Vec<String> {
+    split_at(text, true)
+}
+
+// Splits data items, which may contain spaces, at commas only.
+pub(crate) fn split_items(text: &str) -> Vec<String> {
+    split_at(text, false)
+}
+
+fn split_at(text: &str, whitespace: bool) -> Vec<String> {
+    let mut ret = vec![];
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\'' || c == '"' {
+            let len = quoted_len(&text[i..]);
+            current += &text[i..i + len];
+            while chars.as_str().len() > text.len() - i - len {
+                chars.next();
+            }
+            continue;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth == 0 && (c == ',' || (whitespace && c.is_whitespace())) {
+            if !current.is_empty() {
+                ret.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        ret.push(current);
+    }
+    ret
+}
+
+pub(crate) fn is_mnemonic(name: &str) -> bool {
+    matches!(name, "push" | "add" | "sub" | "mul" | "div" | "dup" | "dump" | "print" | "jmp" | "eq" | "neq" | "jnz" | "halt" | "blind" | "loadb" | "loadw")
+}
+
+fn expand_line(macros: &[Macro], file: &Option<String>, line: usize, text: &str, depth: usize, expansions: &mut usize, out: &mut Vec<SourceLine>) -> Result<(), AsmError> {
+    let toks = tokens(text);
+    let mut rest = text.trim_start();
+    let mut first = toks.first().map(|(_col, t)| *t);
+    if let Some(label) = first.filter(|t| t.ends_with(':')) {
+        rest = rest[label.len()..].trim_start();
+        first = toks.get(1).map(|(_col, t)| *t);
+    }
+    let m = match first.and_then(|name| macros.iter().find(|m| m.name == name)) {
+        Some(m) => m,
+        None => {
+            out.push(SourceLine { file: file.clone(), line, text: text.to_string() });
+            return Ok(());
+        }
+    };
+    if depth == MACRO_DEPTH {
+        return Err(AsmError::at(file, line, format!("Macro `{}` nested more than {} levels deep", m.name, MACRO_DEPTH)));
+    }
+    // A label in front of an invocation marks the first expanded instruction.
+    if rest.len() < text.trim_start().len() {
+        out.push(SourceLine { file: file.clone(), line, text: toks[0].1.to_string() });
+    }
+    let args = split_args(&rest[m.name.len()..]);
+    if args.len() != m.params.len() {
+        return Err(AsmError::at(file, line, format!("Macro `{}` takes {} arguments, got {}", m.name, m.params.len(), args.len())));
+    }
+    // Labels defined in the body are renamed per expansion so that a macro
+    // can be used more than once.
+    *expansions += 1;
+    let id = *expansions;
+    let locals: Vec<&str> = m.body.iter()
+        .filter_map(|l| tokens(l).first().and_then(|(_col, t)| t.strip_suffix(':')))
+        .collect();
+    for body in &m.body {
+        let expanded = map_words(body, |word, param| {
+            if param {
+                match m.params.iter().position(|p| p == word) {
+                    Some(i) => Ok(Some(args[i].clone())),
+                    None => Err(AsmError::at(file, line, format!("Unknown parameter `\\{}` in macro `{}`", word, m.name)))
+                }
+            } else if locals.contains(&word) {
+                Ok(Some(format!("{}.{}", word, id)))
+            } else {
+                Ok(None)
+            }
+        })?;
+        expand_line(macros, file, line, &expanded, depth + 1, expansions, out)?;
+    }
+    Ok(())
+}
+
+struct Expander<'a> {
+    include_dirs: &'a [String],
+    macros: Vec<Macro>,
+    expansions: usize,
+    // Canonical paths of the files currently being read, outermost first.
+    open: Vec<PathBuf>,
+    out: Vec<SourceLine>
+}
+
+impl Expander<'_> {
+    fn source(&mut self, source: &str, file: &Option<String>, dir: &Path) -> Result<(), AsmError> {
+        let mut lines = source.lines().enumerate();
+        while let Some((n, text)) = lines.next() {
+            let line = n + 1;
+            let text = split_comment(text).0;
+            let toks = tokens(text);
+            match toks.first().map(|(_col, t)| *t) {
+                Some(".include") => self.include(file, line, text, dir)?,
+                Some(".macro") => {
+                    let words = split_args(&text.trim_start()[".macro".len()..]);
+                    let name = match words.first() {
+                        Some(name) if is_label_name(name) && !is_mnemonic(name) => name.clone(),
+                        Some(name) => return Err(AsmError::at(file, line, format!("Invalid macro name `{}`", name))),
+                        None => return Err(AsmError::at(file, line, "Missing macro name".to_string()))
+                    };
+                    if self.macros.iter().any(|m| m.name == name) {
+                        return Err(AsmError::at(file, line, format!("Duplicate macro `{}`", name)));
+                    }
+                    let params = words[1..].to_vec();
+                    if let Some(p) = params.iter().find(|p| !is_label_name(p)) {
+                        return Err(AsmError::at(file, line, format!("Invalid parameter name `{}`", p)));
+                    }
+                    let mut body = vec![];
+                    loop {
+                        match lines.next() {
+                            Some((_n, text)) => match tokens(split_comment(text).0).first().map(|(_col, t)| *t) {
+                                Some(".endm") => break,
+                                Some(".macro") => return Err(AsmError::at(file, line, format!("Macro `{}` contains another .macro", name))),
+                                _ => body.push(split_comment(text).0.to_string())
+                            },
+                            None => return Err(AsmError::at(file, line, format!("Macro `{}` is missing its .endm", name)))
+                        }
+                    }
+                    self.macros.push(Macro { name, params, body });
+                }
+                Some(".endm") => return Err(AsmError::at(file, line, ".endm without .macro".to_string())),
+                _ => expand_line(&self.macros, file, line, text, 0, &mut self.expansions, &mut self.out)?
+            }
+        }
+        Ok(())
+    }
+
+    // Looks next to the including file first, then in the include paths.
+    fn include(&mut self, file: &Option<String>, line: usize, text: &str, dir: &Path) -> Result<(), AsmError> {
+        let arg = text.trim_start()[".include".len()..].trim();
+        let name = match arg.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+            Some(name) if !name.is_empty() => name,
+            _ => return Err(AsmError::at(file, line, "Expected a quoted path after .include".to_string()))
+        };
+        let path = match std::iter::once(dir.join(name)).chain(self.include_dirs.iter().map(|d| Path::new(d).join(name))).find(|p| p.is_file()) {
+            Some(path) => path,
+            None => return Err(AsmError::at(file, line, format!("Cannot find include file `{}`", name)))
+        };
+        let canonical = path.canonicalize().unwrap_or(path.clone());
+        if let Some(start) = self.open.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = self.open[start..].iter().chain(std::iter::once(&canonical)).map(|p| p.display().to_string()).collect();
+            return Err(AsmError::at(file, line, format!("Include cycle {}", cycle.join(" -> "))));
+        }
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| AsmError::at(file, line, format!("Cannot read include file `{}`: {}", name, e)))?;
+        self.open.push(canonical);
+        let result = self.source(&source, &Some(path.display().to_string()), path.parent().unwrap_or(Path::new("")));
+        self.open.pop();
+        result.map_err(|mut e| {
+            e.included_from.push((file.clone(), line));
+            e
+        })
+    }
+}
+
+// An open `.if`, `.while` or `.loop` block: its directive, number and the
+// place it was opened, and for `.if` whether its `.else` was seen.
+struct Block {
+    kind: &'static str,
+    id: usize,
+    file: Option<String>,
+    line: usize,
+    has_else: bool
+}
+
+impl Block {
+    fn closer(&self) -> &'static str {
+        match self.kind {
+            ".if" => ".endif",
+            ".while" => ".endwhile",
+            _ => ".endloop"
+        }
+    }
+    fn label(&self, part: &str) -> String {
+        format!("{}.{}.{}", self.kind, self.id, part)
+    }
+}
+
+const BLOCK_DIRECTIVES: &[&str] = &[".if", ".else", ".endif", ".while", ".endwhile", ".loop", ".endloop"];
+
+fn place(file: &Option<String>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file, line),
+        None => format!("line {}", line)
+    }
+}
+
+// Lowers structured control flow to jumps to generated labels such as
+// `.if.1.else`. `.if` and `.while` consume the top of the stack and take any
+// value but 0 as true; a `.while` body must leave the next condition on the
+// stack. `.loop N` runs its body N times, or as many times as the top of the
+// stack says if N is left out, with the remaining count on top of the stack
+// which the body must leave in place.
+//
+// JNZ only jumps on 1 and leaves any other value on the stack, so a condition
+// is first turned into 0 or 1 with `push 0, neq` and on the false path the 0
+// is turned into a 1 that the jump away consumes.
+fn lower_blocks(source: Vec<SourceLine>) -> Result<Vec<SourceLine>, AsmError> {
+    let mut out = vec![];
+    let mut open: Vec<Block> = vec![];
+    let mut count = 0;
+    for SourceLine { file, line, text } in source {
+        let mut toks = tokens(&text);
+        // A label in front of a directive goes on a line of its own.
+        if toks.len() > 1 && toks[0].1.ends_with(':') && BLOCK_DIRECTIVES.contains(&toks[1].1) {
+            out.push(SourceLine { file: file.clone(), line, text: text[..text.len() - after_token(&text, toks[0]).len()].to_string() });
+            toks.remove(0);
+        }
+        let directive = match toks.first().and_then(|(_col, t)| BLOCK_DIRECTIVES.iter().find(|d| *d == t)) {
+            Some(d) => *d,
+            None => {
+                out.push(SourceLine { file, line, text });
+                continue;
+            }
+        };
+        let operand = after_token(&text, toks[0]).trim();
+        if !operand.is_empty() && directive != ".loop" {
+            return Err(AsmError::at(&file, line, format!("`{}` takes no operand", directive)));
+        }
+        let indent = &text[..text.len() - text.trim_start().len()];
+        let mut lines = vec![];
+        let mut emit = |text: String| lines.push(text);
+        match directive {
+            ".if" | ".while" | ".loop" => {
+                count += 1;
+                let block = Block { kind: directive, id: count, file: file.clone(), line, has_else: false };
+                let (then, otherwise) = match directive {
+                    ".if" => (block.label("then"), block.label("else")),
+                    _ => (block.label("body"), block.label("end"))
+                };
+                match directive {
+                    ".if" => {}
+                    ".while" => emit(format!("{}:", block.label("top"))),
+                    _ => {
+                        if !operand.is_empty() {
+                            emit(format!("push {}", operand));
+                        }
+                        emit(format!("{}:", block.label("top")));
+                        emit("dup 0".to_string());
+                    }
+                }
+                emit("push 0".to_string());
+                emit("neq".to_string());
+                emit(format!("jnz {}", then));
+                // A finished loop also drops its count of 0.
+                if directive == ".loop" {
+                    emit("add".to_string());
+                }
+                emit("push 1".to_string());
+                emit("add".to_string());
+                emit(format!("jnz {}", otherwise));
+                emit(format!("{}:", then));
+                open.push(block);
+            }
+            _ => {
+                let block = match open.pop() {
+                    Some(block) => block,
+                    None if directive == ".else" => return Err(AsmError::at(&file, line, "`.else` without `.if`".to_string())),
+                    None => return Err(AsmError::at(&file, line, format!("`{}` without a block to close", directive)))
+                };
+                let mismatch = match directive {
+                    ".else" => block.kind != ".if" || block.has_else,
+                    _ => block.closer() != directive
+                };
+                if mismatch {
+                    let message = match (directive, block.kind) {
+                        (".else", ".if") => format!("`.if` opened here has a second `.else` on {}", place(&file, line)),
+                        (".else", kind) => format!("`{}` opened here contains an `.else` on {}", kind, place(&file, line)),
+                        _ => format!("`{}` opened here is closed by `{}` on {} instead of `{}`", block.kind, directive, place(&file, line), block.closer())
+                    };
+                    return Err(AsmError::at(&block.file, block.line, message));
+                }
+                match directive {
+                    ".else" => {
+                        emit(format!("jmp {}", block.label("end")));
+                        emit(format!("{}:", block.label("else")));
+                        open.push(Block { has_else: true, ..block });
+                    }
+                    ".endif" => {
+                        if !block.has_else {
+                            emit(format!("{}:", block.label("else")));
+                        }
+                        emit(format!("{}:", block.label("end")));
+                    }
+                    ".endwhile" => {
+                        emit(format!("jmp {}", block.label("top")));
+                        emit(format!("{}:", block.label("end")));
+                    }
+                    _ => {
+                        emit("push 1".to_string());
+                        emit("sub".to_string());
+                        emit(format!("jmp {}", block.label("top")));
+                        emit(format!("{}:", block.label("end")));
+                    }
+                }
+            }
+        }
+        out.extend(lines.into_iter().map(|l| SourceLine { file: file.clone(), line, text: format!("{}{}", indent, l) }));
+    }
+    match open.pop() {
+        Some(block) => Err(AsmError::at(&block.file, block.line, format!("`{}` is missing its `{}`", block.kind, block.closer()))),
+        None => Ok(out)
+    }
+}
+
+// Expands `.include "file.vm"` and `.macro name params ... .endm`
+// definitions, drops `;` comments and lowers control flow blocks.
+// Parameters are referenced as `\param` in the body; arguments are separated
+// by commas or whitespace. Includes are resolved relative to the directory of
+// `path`, or the working directory when there is none.
+pub fn expand_with(source: &str, path: Option<&str>, include_dirs: &[String]) -> Result<Vec<SourceLine>, AsmError> {
+    let mut expander = Expander { include_dirs, macros: vec![], expansions: 0, open: vec![], out: vec![] };
+    let dir = path.and_then(|p| Path::new(p).parent()).unwrap_or(Path::new(""));
+    if let Some(canonical) = path.and_then(|p| Path::new(p).canonicalize().ok()) {
+        expander.open.push(canonical);
+    }
+    expander.source(source, &None, dir)?;
+    lower_blocks(expander.out)
+}
+
+pub fn expand(source: &str) -> Result<Vec<SourceLine>, AsmError> {
+    expand_with(source, None, &[])
+}
+
+// The text after the token at the (1 based) column `col`.
+pub(crate) fn after_token<'a>(text: &'a str, (col, tok): (usize, &str)) -> &'a str {
+    let start = text.char_indices().nth(col - 1).map(|(i, _c)| i).unwrap_or(text.len());
+    &text[start + tok.len()..]
+}
+
+struct Const<'a> {
+    name: &'a str,
+    expr: Expr,
+    file: &'a Option<String>,
+    line: usize
+}
+
+// The value of an expression in an object file: a number plus multiples of
+// addresses the linker has yet to fix, the start of the object's code or data
+// or an imported symbol. Outside of object files `terms` is always empty.
+#[derive(Debug, Clone, PartialEq)]
+struct Linear {
+    value: Word,
+    terms: Vec<(Target, i64)>
+}
+
+impl From<Word> for Linear {
+    fn from(value: Word) -> Linear {
+        Linear { value, terms: vec![] }
+    }
+}
+
+impl Linear {
+    fn address(offset: usize, target: Target) -> Linear {
+        Linear { value: offset as Word, terms: vec![(target, 1)] }
+    }
+    fn scale(mut self, by: Word) -> Linear {
+        self.value = self.value.wrapping_mul(by);
+        for (_target, factor) in &mut self.terms {
+            *factor = factor.wrapping_mul(by as i64);
+        }
+        self.terms.retain(|(_target, factor)| *factor != 0);
+        self
+    }
+    fn plus(mut self, other: Linear) -> Linear {
+        self.value = self.value.wrapping_add(other.value);
+        for (target, factor) in other.terms {
+            match self.terms.iter_mut().find(|(t, _f)| *t == target) {
+                Some((_t, f)) => *f = f.wrapping_add(factor),
+                None => self.terms.push((target, factor))
+            }
+        }
+        self.terms.retain(|(_target, factor)| *factor != 0);
+        self
+    }
+    // Numbers combine as usual; addresses can only be added, subtracted and
+    // multiplied by a number, since that is all relocations can express.
+    fn apply(op: BinOp, x: Linear, y: Linear) -> Result<Linear, String> {
+        match op {
+            _ if x.terms.is_empty() && y.terms.is_empty() => op.apply(x.value, y.value).map(Linear::from),
+            BinOp::Add => Ok(x.plus(y)),
+            BinOp::Sub => Ok(x.plus(y.scale(Word::MAX))),
+            BinOp::Mul if x.terms.is_empty() => Ok(y.scale(x.value)),
+            BinOp::Mul if y.terms.is_empty() => Ok(x.scale(y.value)),
+            _ => Err(format!("`{}` cannot be applied to an address that is only known after linking", op))
+        }
+    }
+}
+
+// Evaluates constants on demand so they can refer to each other and to
+// labels in any order. When assembling an object, labels are addresses
+// relative to the object and imported names are left to the linker.
+struct Resolver<'a> {
+    consts: &'a [Const<'a>],
+    labels: &'a [(String, usize)],
+    data_labels: &'a [(String, usize)],
+    imports: &'a [String],
+    object: bool,
+    values: Vec<Option<Linear>>,
+    evaluating: Vec<usize>
+}
+
+impl<'a> Resolver<'a> {
+    fn constant(&mut self, i: usize) -> Result<Linear, AsmError> {
+        if let Some(value) = &self.values[i] {
+            return Ok(value.clone());
+        }
+        let consts = self.consts;
+        let c = &consts[i];
+        if self.evaluating.contains(&i) {
+            return Err(AsmError::at(c.file, c.line, format!("Constant `{}` is defined in terms of itself", c.name)));
+        }
+        self.evaluating.push(i);
+        let value = self.eval(&c.expr, c.file, c.line);
+        self.evaluating.pop();
+        self.values[i] = Some(value.clone()?);
+        value
+    }
+    fn lookup(&mut self, name: &str, file: &Option<String>, line: usize) -> Result<Linear, AsmError> {
+        if let Some(i) = self.consts.iter().position(|c| c.name == name) {
+            return self.constant(i);
+        }
+        if let Some((_l, i)) = self.labels.iter().find(|(l, _i)| l == name) {
+            return Ok(if self.object { Linear::address(*i, Target::Code) } else { Linear::from(*i as Word) });
+        }
+        if let Some((_l, o)) = self.data_labels.iter().find(|(l, _o)| l == name) {
+            return Ok(if self.object { Linear::address(*o, Target::Data) } else { Linear::from(*o as Word) });
+        }
+        match self.imports.iter().any(|i| i == name) {
+            true if self.object => Ok(Linear::address(0, Target::Symbol(name.to_string()))),
+            true => Err(AsmError::at(file, line, format!("`{}` is imported; compile with `-c` and link the object", name))),
+            false => Err(AsmError::at(file, line, format!("Undefined name `{}`", name)))
+        }
+    }
+    fn eval(&mut self, expr: &Expr, file: &Option<String>, line: usize) -> Result<Linear, AsmError> {
+        expr.eval(&mut |name| self.lookup(name, file, line), &|op, x, y| Linear::apply(op, x, y).map_err(|message| AsmError::at(file, line, message)))
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    assemble_lines(&expand(source)?)
+}
+
+// Assembles a source read from `path`, which is used to resolve includes.
+pub fn assemble_with(source: &str, path: Option<&str>, include_dirs: &[String]) -> Result<Assembly, AsmError> {
+    assemble_lines(&expand_with(source, path, include_dirs)?)
+}
+
+// Like `assemble_with`, for an object file: labels are relative to the
+// object, `.import`ed names may be used and `relocs` lists the operands and
+// data words the linker has to adjust.
+pub fn assemble_object(source: &str, path: Option<&str>, include_dirs: &[String]) -> Result<Assembly, AsmError> {
+    assemble_lines_as(&expand_with(source, path, include_dirs)?, true)
+}
+
+// Labels are written `name:`, either alone on a line or in front of an
+// instruction. Operands are expressions over numbers, labels and constants
+// defined with `.const NAME expr`; they are evaluated once every name is known.
+// After `.data`, lines hold `.word`, `.bytes` and `.string` directives instead
+// of instructions and labels name offsets into the data segment, until `.text`
+// switches back. `.proc name (in -- out)` ... `.endp` declares a procedure,
+// labelled `name`, whose body must take `in` values and leave `out` on every
+// path out of it. `.export name, ...` and `.import name, ...` declare the
+// labels an object file shares with the others it is linked with.
+pub fn assemble_lines(source: &[SourceLine]) -> Result<Assembly, AsmError> {
+    assemble_lines_as(source, false)
+}
+
+fn assemble_lines_as(source: &[SourceLine], object: bool) -> Result<Assembly, AsmError> {
+    let mut program = Vec::<Instruction>::new();
+    let mut lines = Vec::<usize>::new();
+    let mut columns = Vec::<usize>::new();
+    let mut files = Vec::<Option<String>>::new();
+    let mut labels = Vec::<(String, usize)>::new();
+    let mut consts = Vec::<Const>::new();
+    let mut operands = Vec::<(usize, Expr, &Option<String>, usize)>::new();
+    let mut data = Vec::<u8>::new();
+    let mut data_labels = Vec::<(String, usize)>::new();
+    // Offset, width in bytes and value of every `.word` and `.bytes` item.
+    let mut items = Vec::<(usize, usize, Expr, &Option<String>, usize)>::new();
+    let mut in_data = false;
+    let mut procs = Vec::<Proc>::new();
+    // Where every procedure was declared, and the one still open.
+    let mut proc_lines = Vec::<(&Option<String>, usize)>::new();
+    let mut open_proc: Option<usize> = None;
+    let mut exports = Vec::<(String, &Option<String>, usize)>::new();
+    let mut imports = Vec::<String>::new();
+    let defined = |labels: &[(String, usize)], data_labels: &[(String, usize)], consts: &[Const], imports: &[String], name: &str| {
+        labels.iter().chain(data_labels).any(|(l, _i)| l == name) || consts.iter().any(|c| c.name == name) || imports.iter().any(|i| i == name)
+    };
+    for SourceLine { file, line, text } in source {
+        let line = *line;
+        let mut toks = tokens(text);
+        if let Some(name) = toks.first().and_then(|(_col, t)| t.strip_suffix(':')) {
+            if !is_label_name(name) {
+                return Err(AsmError::at(file, line, format!("Invalid label name `{}`", name)));
+            }
+            if defined(&labels, &data_labels, &consts, &imports, name) {
+                return Err(AsmError::at(file, line, format!("Duplicate label `{}`", name)));
+            }
+            match in_data {
+                true => data_labels.push((name.to_string(), data.len())),
+                false => labels.push((name.to_string(), program.len()))
+            }
+            toks.remove(0);
+        }
+        if toks.is_empty() {
+            continue;
+        }
+        let rest = after_token(text, toks[0]);
+        if toks[0].1 == ".const" {
+            let name = match toks.get(1) {
+                Some((_col, name)) if is_label_name(name) => *name,
+                Some((_col, name)) => return Err(AsmError::at(file, line, format!("Invalid constant name `{}`", name))),
+                None => return Err(AsmError::at(file, line, "Missing constant name".to_string()))
+            };
+            if defined(&labels, &data_labels, &consts, &imports, name) {
+                return Err(AsmError::at(file, line, format!("Duplicate constant `{}`", name)));
+            }
+            let expr = Expr::parse(after_token(text, toks[1])).map_err(|message| AsmError::at(file, line, message))?;
+            consts.push(Const { name, expr, file, line });
+            continue;
+        }
+        match toks[0].1 {
+            ".export" | ".import" => {
+                let names = split_items(rest);
+                if names.is_empty() {
+                    return Err(AsmError::at(file, line, format!("Missing operand for `{}`", toks[0].1)));
+                }
+                for name in names.iter().map(|n| n.trim().to_string()) {
+                    if !is_label_name(&name) {
+                        return Err(AsmError::at(file, line, format!("Invalid label name `{}`", name)));
+                    }
+                    if toks[0].1 == ".export" {
+                        exports.push((name, file, line));
+                    } else if defined(&labels, &data_labels, &consts, &imports, &name) {
+                        return Err(AsmError::at(file, line, format!("Duplicate label `{}`", name)));
+                    } else {
+                        imports.push(name);
+                    }
+                }
+                continue;
+            }
+            ".proc" if in_data => return Err(AsmError::at(file, line, "`.proc` inside `.data`".to_string())),
+            ".proc" => {
+                if let Some(open) = open_proc {
+                    let (open_file, open_line) = proc_lines[open];
+                    return Err(AsmError::at(file, line, format!("`.proc` inside `{}`, which was opened on {}", procs[open].name, place(open_file, open_line))));
+                }
+                let name = match toks.get(1) {
+                    Some((_col, name)) if is_label_name(name) => *name,
+                    Some((_col, name)) => return Err(AsmError::at(file, line, format!("Invalid procedure name `{}`", name))),
+                    None => return Err(AsmError::at(file, line, "Missing procedure name".to_string()))
+                };
+                if defined(&labels, &data_labels, &consts, &imports, name) {
+                    return Err(AsmError::at(file, line, format!("Duplicate label `{}`", name)));
+                }
+                let effect = after_token(text, toks[1]).trim();
+                let (inputs, outputs) = match Proc::parse_effect(effect) {
+                    Some(counts) => counts,
+                    None => return Err(AsmError::at(file, line, format!("Expected a stack effect such as `( a b -- c )` after `.proc {}`", name)))
+                };
+                labels.push((name.to_string(), program.len()));
+                open_proc = Some(procs.len());
+                procs.push(Proc { name: name.to_string(), effect: effect.to_string(), inputs, outputs, start: program.len(), end: program.len() });
+                proc_lines.push((file, line));
+                continue;
+            }
+            ".endp" if !rest.trim().is_empty() => return Err(AsmError::at(file, line, "`.endp` takes no operand".to_string())),
+            ".endp" => match open_proc.take() {
+                Some(open) => {
+                    procs[open].end = program.len();
+                    continue;
+                }
+                None => return Err(AsmError::at(file, line, "`.endp` without `.proc`".to_string()))
+            },
+            ".data" | ".text" if !rest.trim().is_empty() => return Err(AsmError::at(file, line, format!("`{}` takes no operand", toks[0].1))),
+            ".data" | ".text" => {
+                in_data = toks[0].1 == ".data";
+                continue;
+            }
+            ".word" | ".bytes" | ".string" if !in_data => return Err(AsmError::at(file, line, format!("`{}` outside of `.data`", toks[0].1))),
+            ".word" | ".bytes" => {
+                let width = if toks[0].1 == ".word" { 8 } else { 1 };
+                let args = split_items(rest);
+                if args.is_empty() {
+                    return Err(AsmError::at(file, line, format!("Missing operand for `{}`", toks[0].1)));
+                }
+                for arg in args {
+                    let expr = Expr::parse(&arg).map_err(|message| AsmError::at(file, line, message))?;
+                    items.push((data.len(), width, expr, file, line));
+                    data.resize(data.len() + width, 0);
+                }
+                continue;
+            }
+            ".string" => {
+                data.extend(string_literal(rest).map_err(|message| AsmError::at(file, line, message))?);
+                continue;
+            }
+            _ if in_data => return Err(AsmError::at(file, line, "Only `.word`, `.bytes` and `.string` may appear in `.data`".to_string())),
+            _ => {}
+        }
+        let inst = match toks[0].1 {
+            "dump" => Instruction::DUMP,
+            "print" => Instruction::PRINT,
+            "add" => Instruction::ADD,
+            "sub" => Instruction::SUB,
+            "mul" => Instruction::MUL,
+            "div" => Instruction::DIV,
+            "eq" => Instruction::EQ,
+            "neq" => Instruction::NEQ,
+            "halt" => Instruction::HALT,
+            "blind" => Instruction::BLIND,
+            "loadb" => Instruction::LOADB,
+            "loadw" => Instruction::LOADW,
+            "push" => Instruction::PUSH(0),
+            "dup" => Instruction::DUP(0),
+            "jmp" => Instruction::JMP(0),
+            "jnz" => Instruction::JNZ(0),
+            _ => return Err(AsmError::at(file, line, "Invalid syntax".to_string()))
+        };
+        match (inst.operand(), rest.trim().is_empty()) {
+            (Some(_o), true) => return Err(AsmError::at(file, line, format!("Missing operand for `{}`", toks[0].1))),
+            (Some(_o), false) => {
+                let expr = Expr::parse(rest).map_err(|message| AsmError::at(file, line, message))?;
+                operands.push((program.len(), expr, file, line));
+            }
+            (None, false) => return Err(AsmError::at(file, line, format!("`{}` takes no operand", toks[0].1))),
+            (None, true) => {}
+        }
+        program.push(inst);
+        lines.push(line);
+        columns.push(toks[0].0);
+        files.push(file.clone());
+    }
+    if let Some(open) = open_proc {
+        let (file, line) = proc_lines[open];
+        return Err(AsmError::at(file, line, format!("`.proc` `{}` is missing its `.endp`", procs[open].name)));
+    }
+    let mut resolver = Resolver { consts: &consts, labels: &labels, data_labels: &data_labels, imports: &imports, object, values: vec![None; consts.len()], evaluating: vec![] };
+    // Every constant is checked, used or not.
+    for i in 0..consts.len() {
+        resolver.constant(i)?;
+    }
+    let mut relocs = vec![];
+    for (index, expr, file, line) in operands {
+        let value = resolver.eval(&expr, file, line)?;
+        program[index] = program[index].with_operand(value.value);
+        relocs.extend(value.terms.into_iter().map(|(target, factor)| Reloc { place: Place::Code(index), target, factor }));
+    }
+    for (offset, width, expr, file, line) in items {
+        let value = resolver.eval(&expr, file, line)?;
+        if width == 1 && !value.terms.is_empty() {
+            return Err(AsmError::at(file, line, "An address that is only known after linking does not fit in a byte".to_string()));
+        }
+        // Negative bytes down to -128 are stored in two's complement.
+        if width == 1 && value.value > 0xff && value.value < Word::MAX - 127 {
+            let shown = match (value.value as i64) < 0 {
+                true => (value.value as i64).to_string(),
+                false => value.value.to_string()
+            };
+            return Err(AsmError::at(file, line, format!("{} does not fit in a byte", shown)));
+        }
+        data[offset..offset + width].copy_from_slice(&value.value.to_le_bytes()[..width]);
+        relocs.extend(value.terms.into_iter().map(|(target, factor)| Reloc { place: Place::Data(offset), target, factor }));
+    }
+    for (name, file, line) in &exports {
+        if !labels.iter().chain(&data_labels).any(|(l, _i)| l == name) {
+            return Err(AsmError::at(file, *line, format!("Exported name `{}` is not a label", name)));
+        }
+    }
+    // Jumps to imported labels leave the object, and so every procedure.
+    let mut checked = program.clone();
+    for r in &relocs {
+        if let (Place::Code(i), Target::Symbol(_name)) = (r.place, &r.target) {
+            if let Instruction::JMP(_) | Instruction::JNZ(_) = checked[i] {
+                checked[i] = checked[i].with_operand(Word::MAX);
+            }
+        }
+    }
+    for (proc, (file, line)) in procs.iter().zip(&proc_lines) {
+        check_proc(&checked, proc).map_err(|(pc, message)| match pc < proc.end {
+            true => AsmError::at(&files[pc], lines[pc], message),
+            // An empty body.
+            false => AsmError::at(file, *line, message)
+        })?;
+    }
+    let consts = consts.iter().zip(resolver.values).map(|(c, v)| (c.name.to_string(), v.map_or(0, |v| v.value))).collect();
+    let exports = exports.into_iter().map(|(name, _file, _line)| name).collect();
+    Ok(Assembly { program, lines, columns, files, labels, consts, data, data_labels, procs, exports, imports, relocs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::vm::{RunOutcome, VM};
+
+    fn expanded(source: &str) -> Vec<String> {
+        expand(source).unwrap().into_iter().map(|l| l.text.trim().to_string()).filter(|t| !t.is_empty()).collect()
+    }
+
+    fn error(result: Result<impl fmt::Debug, AsmError>) -> String {
+        result.unwrap_err().message
+    }
+
+    #[test]
+    fn macros_substitute_parameters() {
+        let source = ".macro twice op, n\n  push \\n\n  \\op\n.endm\npush 1\ntwice add, 2\ntwice mul 3\n";
+        assert_eq!(expanded(source), ["push 1", "push 2", "add", "push 3", "mul"]);
+        let lines = expand(source).unwrap();
+        assert_eq!(lines.iter().filter(|l| l.text.contains("push 3")).map(|l| l.line).collect::<Vec<_>>(), [7]);
+    }
+
+    #[test]
+    fn macro_labels_are_unique_per_expansion() {
+        let source = ".macro spin\nagain: jmp again\n.endm\nspin\nspin\n";
+        assert_eq!(expanded(source), ["again.1: jmp again.1", "again.2: jmp again.2"]);
+        assert!(assemble(source).is_ok());
+    }
+
+    #[test]
+    fn macros_invoke_macros() {
+        let source = ".macro one\npush 1\n.endm\n.macro two\none\none\nadd\n.endm\ntwo\n";
+        assert_eq!(expanded(source), ["push 1", "push 1", "add"]);
+    }
+
+    #[test]
+    fn macro_errors() {
+        assert_eq!(error(expand(".macro m a\npush \\a\n.endm\nm 1, 2\n")), "Macro `m` takes 1 arguments, got 2");
+        assert_eq!(error(expand(".macro m\npush \\b\n.endm\nm\n")), "Unknown parameter `\\b` in macro `m`");
+        assert_eq!(error(expand(".macro m\npush 1\n")), "Macro `m` is missing its .endm");
+        assert_eq!(error(expand(".endm\n")), ".endm without .macro");
+        assert_eq!(error(expand(".macro m\n.endm\n.macro m\n.endm\n")), "Duplicate macro `m`");
+        assert!(error(expand(".macro m\nm\n.endm\nm\n")).contains("levels deep"));
+    }
+
+    // A fresh directory holding `files`, for include tests.
+    fn dir_with(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("lvm-asm-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, text) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_resolve_next_to_the_includer_then_in_include_dirs() {
+        let dir = dir_with("include", &[
+            ("main.vm", "push 1\n.include \"sub/a.vm\"\n.include \"lib.vm\"\n"),
+            ("sub/a.vm", ".include \"b.vm\"\npush 2\n"),
+            ("sub/b.vm", "push 3\n"),
+            ("libs/lib.vm", "add\n")
+        ]);
+        let main = dir.join("main.vm").display().to_string();
+        let libs = vec![dir.join("libs").display().to_string()];
+        let lines = expand_with(&std::fs::read_to_string(&main).unwrap(), Some(&main), &libs).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|l| l.text.trim()).filter(|t| !t.is_empty()).collect();
+        assert_eq!(texts, ["push 1", "push 3", "push 2", "add"]);
+        let b = lines.iter().find(|l| l.text.contains("push 3")).unwrap();
+        assert!(b.file.as_deref().is_some_and(|f| f.ends_with("b.vm")));
+        assert_eq!(b.line, 1);
+        let e = expand_with(&std::fs::read_to_string(&main).unwrap(), Some(&main), &[]).unwrap_err();
+        assert_eq!((e.message.as_str(), e.line), ("Cannot find include file `lib.vm`", 3));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn include_cycles_and_errors_in_included_files() {
+        let dir = dir_with("cycle", &[
+            ("a.vm", ".include \"b.vm\"\n"),
+            ("b.vm", "push 1\n.include \"a.vm\"\n"),
+            ("bad.vm", "push 1\nfrob\n")
+        ]);
+        let a = dir.join("a.vm").display().to_string();
+        let e = assemble_with(".include \"a.vm\"\n", Some(&a), &[]).unwrap_err();
+        assert!(e.message.starts_with("Include cycle "), "{}", e.message);
+        let bad = dir.join("main.vm").display().to_string();
+        let e = assemble_with("push 0\n.include \"bad.vm\"\n", Some(&bad), &[]).unwrap_err();
+        assert_eq!(e.message, "Invalid syntax");
+        assert_eq!(e.line, 2);
+        assert!(e.file.is_some_and(|f| f.ends_with("bad.vm")));
+        assert_eq!(error(expand(".include lib.vm\n")), "Expected a quoted path after .include");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Assembles and runs `source`, returning what it printed and the outcome.
+    fn run(source: &str) -> (String, RunOutcome) {
+        let assembly = assemble(source).unwrap();
+        let output = Output::default();
+        let mut vm = VM::init();
+        vm.set_output(Box::new(output.clone()));
+        vm.load_program(assembly.program);
+        vm.set_data(assembly.data);
+        let outcome = vm.run_program();
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (printed, outcome)
+    }
+
+    #[test]
+    fn data_directives_fill_the_data_segment() {
+        let source = ".const N 3\n.data\nw: .word 0x0102, N * 100\nb: .bytes -1, 'A', 0x80\ns: .string \"hi\\n\"\n.text\npush w + 8\nloadw\nhalt\n";
+        let assembly = assemble(source).unwrap();
+        let mut expected = vec![2, 1, 0, 0, 0, 0, 0, 0, 44, 1, 0, 0, 0, 0, 0, 0, 0xff, b'A', 0x80];
+        expected.extend(b"hi\n");
+        assert_eq!(assembly.data, expected);
+        assert_eq!(assembly.data_labels, [("w".to_string(), 0), ("b".to_string(), 16), ("s".to_string(), 19)]);
+        assert_eq!(run(source).1, RunOutcome::Halted(300));
+        assert_eq!(run(".data\nb: .bytes -128\n.text\npush b\nloadb\nhalt\n").1, RunOutcome::Halted(0x80));
+    }
+
+    #[test]
+    fn data_errors() {
+        assert_eq!(error(assemble(".data\n.bytes 256\n")), "256 does not fit in a byte");
+        assert_eq!(error(assemble(".data\n.bytes -129\n")), "-129 does not fit in a byte");
+        assert_eq!(error(assemble(".word 1\n")), "`.word` outside of `.data`");
+        assert_eq!(error(assemble(".data\npush 1\n")), "Only `.word`, `.bytes` and `.string` may appear in `.data`");
+        assert_eq!(error(assemble(".data\n.word\n")), "Missing operand for `.word`");
+    }
+
+    #[test]
+    fn if_else_takes_one_branch() {
+        let choose = |cond: u64| run(&format!("push {}\n.if\n    push 111\n.else\n    push 222\n.endif\nhalt\n", cond)).1;
+        assert_eq!(choose(1), RunOutcome::Halted(111));
+        assert_eq!(choose(7), RunOutcome::Halted(111));
+        assert_eq!(choose(0), RunOutcome::Halted(222));
+        assert_eq!(run("push 0\n.if\n    push 1\n    print\n.endif\npush 5\nhalt\n"), (String::new(), RunOutcome::Halted(5)));
+    }
+
+    #[test]
+    fn loops_repeat_their_body() {
+        assert_eq!(run(".loop 3\n    print\n.endloop\n").0, "3\n2\n1\n");
+        assert_eq!(run("push 2\n.loop\n    print\n.endloop\n").0, "2\n1\n");
+        assert_eq!(run(".loop 0\n    print\n.endloop\n").0, "");
+        // Counts down, leaving the next condition on every pass.
+        let source = "push 3\ndup 0\n.while\n    print\n    push 1\n    sub\n    dup 0\n.endwhile\nhalt\n";
+        assert_eq!(run(source), ("3\n2\n1\n".to_string(), RunOutcome::Halted(0)));
+        assert_eq!(run("push 0\n.while\n    push 1\n    print\n    push 0\n.endwhile\n").0, "");
+    }
+
+    #[test]
+    fn mismatched_blocks() {
+        assert_eq!(error(assemble(".if\npush 1\n")), "`.if` is missing its `.endif`");
+        assert_eq!(error(assemble(".endwhile\n")), "`.endwhile` without a block to close");
+        assert_eq!(error(assemble(".else\n")), "`.else` without `.if`");
+        let e = assemble("push 1\n.while\n.endif\n").unwrap_err();
+        assert_eq!((e.message.as_str(), e.line), ("`.while` opened here is closed by `.endif` on line 3 instead of `.endwhile`", 2));
+        assert_eq!(error(assemble(".if\n.else\n.else\n.endif\n")), "`.if` opened here has a second `.else` on line 3");
+        assert_eq!(error(assemble(".if 1\n.endif\n")), "`.if` takes no operand");
+    }
+}
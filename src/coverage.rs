@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use crate::asm::Assembly;
+use crate::observer::Observer;
+use crate::vm::{Instruction, Word};
+
+// Records which instructions ran and, for every JNZ, how often the jump was
+// taken and not taken.
+pub struct Coverage {
+    program: Vec<Instruction>,
+    hits: Vec<u64>,
+    taken: Vec<u64>,
+    not_taken: Vec<u64>,
+    jumped_from: Option<usize>
+}
+
+impl Coverage {
+    pub fn new(program: &[Instruction]) -> Coverage {
+        Coverage {
+            program: program.to_vec(),
+            hits: vec![0; program.len()],
+            taken: vec![0; program.len()],
+            not_taken: vec![0; program.len()],
+            jumped_from: None
+        }
+    }
+
+    pub fn executed(&self) -> Vec<usize> {
+        (0..self.hits.len()).filter(|pc| self.hits[*pc] > 0).collect()
+    }
+
+    // One lcov record per source file, `path` being the top level one and
+    // included files following in the order they were first used.
+    pub fn lcov(&self, path: &str, assembly: &Assembly) -> String {
+        let mut files = vec![&None];
+        for file in &assembly.files {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        files.into_iter().map(|file| self.record(path, file, assembly)).collect()
+    }
+
+    // A line's count is that of its most executed instruction; each JNZ is
+    // a block of two branches, taken first.
+    fn record(&self, path: &str, file: &Option<String>, assembly: &Assembly) -> String {
+        let pcs: Vec<usize> = (0..self.program.len()).filter(|pc| assembly.files[*pc] == *file).collect();
+        let mut lines = BTreeMap::<usize, u64>::new();
+        for pc in &pcs {
+            let count = lines.entry(assembly.lines[*pc]).or_insert(0);
+            *count = (*count).max(self.hits[*pc]);
+        }
+        let source = match file {
+            Some(file) => std::fs::canonicalize(file).map(|p| p.display().to_string()).unwrap_or(file.clone()),
+            None => path.to_string()
+        };
+        let mut ret = format!("TN:\nSF:{}\n", source);
+        let (mut found, mut hit) = (0, 0);
+        for pc in pcs {
+            if let Instruction::JNZ(_) = self.program[pc] {
+                let line = assembly.lines[pc];
+                if self.hits[pc] == 0 {
+                    ret += format!("BRDA:{},{},0,-\nBRDA:{},{},1,-\n", line, pc, line, pc).as_str();
+                } else {
+                    ret += format!("BRDA:{},{},0,{}\nBRDA:{},{},1,{}\n", line, pc, self.taken[pc], line, pc, self.not_taken[pc]).as_str();
+                }
+                found += 2;
+                hit += (self.taken[pc] > 0) as usize + (self.not_taken[pc] > 0) as usize;
+            }
+        }
+        ret += format!("BRF:{}\nBRH:{}\n", found, hit).as_str();
+        for (line, count) in &lines {
+            ret += format!("DA:{},{}\n", line, count).as_str();
+        }
+        ret += format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines.values().filter(|c| **c > 0).count()).as_str();
+        ret
+    }
+
+    pub fn summary(&self) -> String {
+        let executed = self.executed().len();
+        let branches: Vec<usize> = (0..self.program.len()).filter(|pc| matches!(self.program[*pc], Instruction::JNZ(_))).collect();
+        let covered: usize = branches.iter().map(|pc| (self.taken[*pc] > 0) as usize + (self.not_taken[*pc] > 0) as usize).sum();
+        let percent = |a: usize, b: usize| if b == 0 { 100.0 } else { a as f64 * 100.0 / b as f64 };
+        format!("instructions: {}/{} ({:.1}%)\nbranches: {}/{} ({:.1}%)\n",
+            executed, self.program.len(), percent(executed, self.program.len()),
+            covered, branches.len() * 2, percent(covered, branches.len() * 2))
+    }
+}
+
+impl Observer for Coverage {
+    fn before_instruction(&mut self, _pc: usize, _inst: Instruction, _stack: &[Word]) {
+        self.jumped_from = None;
+    }
+    fn after_instruction(&mut self, pc: usize, inst: Instruction, _stack: &[Word]) {
+        if pc >= self.hits.len() {
+            return;
+        }
+        self.hits[pc] += 1;
+        if let Instruction::JNZ(_) = inst {
+            if self.jumped_from == Some(pc) {
+                self.taken[pc] += 1;
+            } else {
+                self.not_taken[pc] += 1;
+            }
+        }
+    }
+    fn on_jump(&mut self, from: usize, _to: usize) {
+        self.jumped_from = Some(from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::asm::assemble_with;
+    use crate::vm::VM;
+
+    fn cover(source: &str, path: Option<&str>) -> (Coverage, Assembly) {
+        let assembly = assemble_with(source, path, &[]).unwrap();
+        let coverage = Rc::new(RefCell::new(Coverage::new(&assembly.program)));
+        let mut vm = VM::init();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.load_program(assembly.program.clone());
+        vm.add_observer(Box::new(coverage.clone()));
+        vm.run_program();
+        drop(vm);
+        (Rc::try_unwrap(coverage).ok().unwrap().into_inner(), assembly)
+    }
+
+    #[test]
+    fn lines_and_branches() {
+        let source = "push 2\nloop:\npush 1\nsub\ndup 0\npush 0\nneq\njnz loop\npush 0\njnz never\nhalt\nnever: push 9\n";
+        let (coverage, assembly) = cover(source, None);
+        assert_eq!(coverage.executed(), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(coverage.summary(), "instructions: 10/11 (90.9%)\nbranches: 3/4 (75.0%)\n");
+        assert_eq!(coverage.lcov("count.vm", &assembly), "TN:\nSF:count.vm\n\
+            BRDA:8,6,0,1\nBRDA:8,6,1,1\nBRDA:10,8,0,0\nBRDA:10,8,1,1\nBRF:4\nBRH:3\n\
+            DA:1,1\nDA:3,2\nDA:4,2\nDA:5,2\nDA:6,2\nDA:7,2\nDA:8,2\nDA:9,1\nDA:10,1\nDA:11,1\nDA:12,0\n\
+            LF:11\nLH:10\nend_of_record\n");
+    }
+
+    #[test]
+    fn unreached_branches_and_empty_programs() {
+        let (coverage, assembly) = cover("push 0\nhalt\njnz 0\n", None);
+        assert_eq!(coverage.lcov("a.vm", &assembly), "TN:\nSF:a.vm\nBRDA:3,2,0,-\nBRDA:3,2,1,-\nBRF:2\nBRH:0\nDA:1,1\nDA:2,1\nDA:3,0\nLF:3\nLH:2\nend_of_record\n");
+        let (coverage, assembly) = cover("", None);
+        assert_eq!(coverage.summary(), "instructions: 0/0 (100.0%)\nbranches: 0/0 (100.0%)\n");
+        assert_eq!(coverage.lcov("e.vm", &assembly), "TN:\nSF:e.vm\nBRF:0\nBRH:0\nLF:0\nLH:0\nend_of_record\n");
+    }
+
+    #[test]
+    fn included_files_get_their_own_record() {
+        let dir = std::env::temp_dir().join(format!("lvm-coverage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.vm"), "twice:\n    dup 0\n    add\n").unwrap();
+        let main = dir.join("main.vm");
+        let source = "push 4\njmp start\n.include \"lib.vm\"\nstart:\nhalt\n";
+        let (coverage, assembly) = cover(source, main.to_str());
+        let lib = std::fs::canonicalize(dir.join("lib.vm")).unwrap();
+        assert_eq!(coverage.lcov("main.vm", &assembly), format!("TN:\nSF:main.vm\nBRF:0\nBRH:0\nDA:1,1\nDA:2,1\nDA:5,1\nLF:3\nLH:3\nend_of_record\n\
+            TN:\nSF:{}\nBRF:0\nBRH:0\nDA:2,0\nDA:3,0\nLF:2\nLH:0\nend_of_record\n", lib.display()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
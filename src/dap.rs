@@ -0,0 +1,580 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use crate::asm::{assemble_with, Assembly};
+use crate::json::Json;
+use crate::vm::{Instruction, StepStatus, VM, Word};
+
+// The VM has a single thread of execution; this is its id on the wire.
+const THREAD_ID: i128 = 1;
+const STACK_REF: i128 = 1;
+const REGISTERS_REF: i128 = 2;
+// Instructions executed between polls for incoming requests while running.
+const SLICE: usize = 1000;
+
+// Collects program output so it can be forwarded as `output` events instead
+// of corrupting the protocol stream on stdout.
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads one `Content-Length` framed message, None on end of input.
+pub(crate) fn read_message<R: BufRead>(input: &mut R) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0u8; length?];
+    input.read_exact(&mut body).ok()?;
+    Json::parse(&String::from_utf8_lossy(&body)).ok()
+}
+
+pub fn write_message<W: Write>(out: &mut W, message: &Json) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+// Messages are read on a separate thread so a running program can still be
+// paused.
+pub fn spawn_reader<R: Read + Send + 'static>(input: R) -> Receiver<Json> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Some(message) = read_message(&mut input) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: i128,
+    vm: VM,
+    assembly: Option<Assembly>,
+    source_path: Option<String>,
+    // Breakpoint instructions per source file, keyed like `Assembly::files`.
+    breakpoints: BTreeMap<Option<String>, BTreeSet<usize>>,
+    output: SharedBuf,
+    stop_on_entry: bool,
+    running: bool,
+    // The pc a `continue` resumes from, whose breakpoint already stopped the
+    // program and must not stop it again.
+    resume_from: Option<usize>,
+    failed: bool,
+    // The `terminated` event was sent; it is sent only once per launch.
+    done: bool,
+    disconnected: bool
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> DapServer<W> {
+        DapServer {
+            out,
+            seq: 0,
+            vm: VM::init(),
+            assembly: None,
+            source_path: None,
+            breakpoints: BTreeMap::new(),
+            output: SharedBuf::default(),
+            stop_on_entry: false,
+            resume_from: None,
+            running: false,
+            failed: false,
+            done: false,
+            disconnected: false
+        }
+    }
+
+    pub fn serve<R: Read + Send + 'static>(&mut self, input: R) -> std::io::Result<()> {
+        let requests = spawn_reader(input);
+        while !self.disconnected {
+            let request = if self.running {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_e) => break
+                }
+            };
+            if let Some(request) = request {
+                self.handle(&request)?;
+            }
+            if self.running {
+                self.run_slice()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> std::io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq", Json::Int(self.seq)));
+        write_message(&mut self.out, &Json::object(fields))
+    }
+    fn event(&mut self, event: &str, body: Json) -> std::io::Result<()> {
+        self.send(vec![("type", Json::str("event")), ("event", Json::str(event)), ("body", body)])
+    }
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> std::io::Result<()> {
+        let mut fields = vec![
+            ("type", Json::str("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Int(0))),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("success", Json::Bool(result.is_ok()))
+        ];
+        match result {
+            Ok(body) => fields.push(("body", body)),
+            Err(message) => fields.push(("message", Json::Str(message)))
+        }
+        self.send(fields)
+    }
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> std::io::Result<()> {
+        self.flush_output()?;
+        let mut body = vec![
+            ("reason", Json::str(reason)),
+            ("threadId", Json::Int(THREAD_ID)),
+            ("allThreadsStopped", Json::Bool(true))
+        ];
+        if let Some(text) = text {
+            body.push(("text", Json::Str(text.clone())));
+            body.push(("description", Json::Str(text)));
+        }
+        self.event("stopped", Json::object(body))
+    }
+    fn flush_output(&mut self) -> std::io::Result<()> {
+        let text = String::from_utf8_lossy(&self.output.0.borrow_mut().split_off(0)).to_string();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.event("output", Json::object(vec![("category", Json::str("stdout")), ("output", Json::Str(text))]))
+    }
+    fn terminate(&mut self, exit_code: i128) -> std::io::Result<()> {
+        self.running = false;
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        self.flush_output()?;
+        self.event("exited", Json::object(vec![("exitCode", Json::Int(exit_code))]))?;
+        self.event("terminated", Json::object(vec![]))
+    }
+
+    fn handle(&mut self, request: &Json) -> std::io::Result<()> {
+        let args = request.get("arguments").cloned().unwrap_or(Json::Object(vec![]));
+        match request.get("command").and_then(|c| c.as_str()).unwrap_or("") {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::Bool(true)),
+                    ("supportsSetVariable", Json::Bool(true))
+                ]);
+                self.respond(request, Ok(capabilities))
+            }
+            "launch" => match self.launch(&args) {
+                Ok(()) => {
+                    self.respond(request, Ok(Json::Null))?;
+                    self.event("initialized", Json::Null)
+                }
+                Err(message) => self.respond(request, Err(message))
+            },
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(&args);
+                self.respond(request, Ok(body))
+            }
+            "setExceptionBreakpoints" => self.respond(request, Ok(Json::object(vec![]))),
+            "configurationDone" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)
+                } else {
+                    self.running = true;
+                    Ok(())
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::Int(THREAD_ID)), ("name", Json::str("main"))]);
+                self.respond(request, Ok(Json::object(vec![("threads", Json::Array(vec![thread]))])))
+            }
+            "stackTrace" => {
+                let frame = self.frame();
+                self.respond(request, Ok(Json::object(vec![
+                    ("stackFrames", Json::Array(vec![frame])),
+                    ("totalFrames", Json::Int(1))
+                ])))
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: i128| Json::object(vec![
+                    ("name", Json::str(name)),
+                    ("variablesReference", Json::Int(reference)),
+                    ("expensive", Json::Bool(false))
+                ]);
+                let scopes = vec![scope("Stack", STACK_REF), scope("Registers", REGISTERS_REF)];
+                self.respond(request, Ok(Json::object(vec![("scopes", Json::Array(scopes))])))
+            }
+            "variables" => {
+                let variables = self.variables(args.get("variablesReference").and_then(|r| r.as_i128()).unwrap_or(0));
+                self.respond(request, Ok(Json::object(vec![("variables", Json::Array(variables))])))
+            }
+            "setVariable" => {
+                let result = self.set_variable(&args);
+                self.respond(request, result)
+            }
+            "continue" => {
+                self.respond(request, Ok(Json::object(vec![("allThreadsContinued", Json::Bool(true))])))?;
+                if self.failed {
+                    self.terminate(self.vm.exit_code().map(|c| c as i128).unwrap_or(202))
+                } else {
+                    self.resume_from = Some(self.vm.pc());
+                    self.running = true;
+                    Ok(())
+                }
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.failed {
+                    return self.terminate(202);
+                }
+                let status = self.vm.step();
+                self.after_step(status, "step")
+            }
+            "pause" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.running {
+                    self.running = false;
+                    self.stopped("pause", None)?;
+                }
+                Ok(())
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                if !self.done {
+                    self.event("terminated", Json::object(vec![]))?;
+                }
+                self.done = true;
+                self.disconnected = true;
+                Ok(())
+            }
+            command => self.respond(request, Err(format!("Unsupported request `{}`.", command)))
+        }
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let path = args.get("program").and_then(|p| p.as_str()).ok_or("Missing `program` in launch arguments.")?;
+        // Nothing of an earlier launch in this session carries over.
+        self.vm = VM::init();
+        self.assembly = None;
+        self.source_path = None;
+        self.breakpoints.clear();
+        self.output.0.borrow_mut().clear();
+        self.running = false;
+        self.resume_from = None;
+        self.failed = false;
+        self.done = false;
+        if path.ends_with(".vm") {
+            let source = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+            let include_dirs: Vec<String> = args.get("includeDirs").and_then(|d| d.as_array())
+                .map(|dirs| dirs.iter().filter_map(|d| d.as_str().map(String::from)).collect())
+                .unwrap_or_default();
+            let assembly = assemble_with(&source, Some(path), &include_dirs).map_err(|e| format!("{}: {}", path, e))?;
+            self.vm.load_program(assembly.program.clone());
+            self.vm.set_data(assembly.data.clone());
+            self.assembly = Some(assembly);
+            self.source_path = Some(path.to_string());
+        } else {
+            self.vm.load_from_file(path).map_err(|e| format!("Could not load {}: {}", path, e))?;
+        }
+        self.vm.set_output(Box::new(self.output.clone()));
+        self.stop_on_entry = args.get("stopOnEntry").and_then(|s| s.as_bool()).unwrap_or(false);
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        let lines: Vec<usize> = args.get("breakpoints").and_then(|b| b.as_array()).map(|bps| {
+            bps.iter().filter_map(|bp| bp.get("line").and_then(|l| l.as_usize())).collect()
+        }).unwrap_or_default();
+        let file = self.file_key(args.get("source").and_then(|s| s.get("path")).and_then(|p| p.as_str()));
+        let mut breakpoints = BTreeSet::new();
+        let mut ret = vec![];
+        for line in lines {
+            let resolved = self.assembly.as_ref().zip(file.as_ref()).and_then(|(a, file)| a.instruction_at(file.as_deref(), line).map(|i| (i, a.lines[i])));
+            match resolved {
+                Some((inst, actual)) => {
+                    breakpoints.insert(inst);
+                    ret.push(Json::object(vec![("verified", Json::Bool(true)), ("line", Json::from(actual))]));
+                }
+                None => ret.push(Json::object(vec![
+                    ("verified", Json::Bool(false)),
+                    ("line", Json::from(line)),
+                    ("message", Json::str("No instruction at or after this line."))
+                ]))
+            }
+        }
+        if let Some(file) = file {
+            self.breakpoints.insert(file, breakpoints);
+        }
+        Json::object(vec![("breakpoints", Json::Array(ret))])
+    }
+
+    // How the assembly names the source at `path`: None for the launched
+    // program, the include path for an included file. Clients without a path
+    // mean the launched program.
+    fn file_key(&self, path: Option<&str>) -> Option<Option<String>> {
+        let path = match path {
+            Some(path) => path,
+            None => return Some(None)
+        };
+        let same = |a: &str| a == path || std::fs::canonicalize(a).ok().is_some_and(|a| std::fs::canonicalize(path).ok() == Some(a));
+        if self.source_path.as_deref().is_some_and(same) {
+            return Some(None);
+        }
+        let assembly = self.assembly.as_ref()?;
+        assembly.files.iter().flatten().find(|f| same(f)).map(|f| Some(f.clone()))
+    }
+
+    fn frame(&self) -> Json {
+        let pc = self.vm.pc();
+        let name = match self.vm.program().get(pc) {
+            Some(inst) => format!("{}: {}", pc, inst),
+            None => format!("{}: <end of program>", pc)
+        };
+        let mut fields = vec![("id", Json::Int(0)), ("name", Json::Str(name)), ("column", Json::Int(1))];
+        match (&self.assembly, &self.source_path) {
+            (Some(assembly), Some(path)) => {
+                let line = assembly.lines.get(pc).or(assembly.lines.last()).copied().unwrap_or(1);
+                let path = match assembly.program.len() {
+                    0 => path.as_str(),
+                    len => assembly.file(pc.min(len - 1), path)
+                };
+                let name = std::path::Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                fields.push(("line", Json::from(line)));
+                fields.push(("source", Json::object(vec![("name", Json::Str(name)), ("path", Json::str(path))])));
+            }
+            _ => fields.push(("line", Json::Int(0)))
+        }
+        Json::object(fields)
+    }
+
+    fn variables(&self, reference: i128) -> Vec<Json> {
+        let var = |name: String, value: String| Json::object(vec![
+            ("name", Json::Str(name)),
+            ("value", Json::Str(value)),
+            ("variablesReference", Json::Int(0))
+        ]);
+        match reference {
+            STACK_REF => self.vm.stack().iter().enumerate().rev().map(|(i, w)| var(format!("[{}]", i), w.to_string())).collect(),
+            REGISTERS_REF => {
+                let next = self.vm.program().get(self.vm.pc()).map(Instruction::to_string).unwrap_or_default();
+                vec![
+                    var("pc".to_string(), self.vm.pc().to_string()),
+                    var("stack_size".to_string(), self.vm.stack().len().to_string()),
+                    var("instruction".to_string(), next)
+                ]
+            }
+            _ => vec![]
+        }
+    }
+
+    fn set_variable(&mut self, args: &Json) -> Result<Json, String> {
+        if args.get("variablesReference").and_then(|r| r.as_i128()) != Some(STACK_REF) {
+            return Err("Only stack slots can be modified.".to_string());
+        }
+        let name = args.get("name").and_then(|n| n.as_str()).unwrap_or("");
+        let slot = name.trim_start_matches('[').trim_end_matches(']').parse::<usize>().map_err(|_e| format!("Unknown variable `{}`.", name))?;
+        let value = args.get("value").and_then(|v| v.as_str()).and_then(|v| v.trim().parse::<Word>().ok()).ok_or("Value must be an unsigned integer.")?;
+        match self.vm.stack_mut().get_mut(slot) {
+            Some(w) => {
+                *w = value;
+                Ok(Json::object(vec![("value", Json::Str(value.to_string()))]))
+            }
+            None => Err(format!("Stack slot {} is not in use.", slot))
+        }
+    }
+
+    fn run_slice(&mut self) -> std::io::Result<()> {
+        for _ in 0..SLICE {
+            let pc = self.vm.pc();
+            if self.resume_from.take() != Some(pc) && self.breakpoints.values().any(|b| b.contains(&pc)) {
+                return self.after_step(StepStatus::Running, "breakpoint");
+            }
+            let status = self.vm.step();
+            if status != StepStatus::Running {
+                return self.after_step(status, "breakpoint");
+            }
+        }
+        self.flush_output()
+    }
+
+    fn after_step(&mut self, status: StepStatus, reason: &str) -> std::io::Result<()> {
+        self.running = false;
+        match status {
+            StepStatus::Running => self.stopped(reason, None),
+            StepStatus::Halted => self.terminate(self.vm.exit_code().map(|c| c as i128).unwrap_or(0)),
+            StepStatus::Error(e) => {
+                self.failed = true;
+                self.stopped("exception", Some(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PROGRAM: &str = "push 2\nprint\npush 3\nprint\npush 7\nhalt\n";
+
+    // Writes `source` to a fresh file and returns its path.
+    fn program(name: &str, source: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lvm-dap-{}-{}.vm", std::process::id(), name));
+        std::fs::write(&path, source).unwrap();
+        path.display().to_string()
+    }
+
+    fn request(seq: i128, command: &str, arguments: Json) -> Json {
+        Json::object(vec![("seq", Json::Int(seq)), ("type", Json::str("request")), ("command", Json::str(command)), ("arguments", arguments)])
+    }
+
+    fn launch(path: &str, stop_on_entry: bool) -> Json {
+        request(2, "launch", Json::object(vec![("program", Json::str(path)), ("stopOnEntry", Json::Bool(stop_on_entry))]))
+    }
+
+    fn breakpoints(lines: &[usize]) -> Json {
+        let lines = lines.iter().map(|l| Json::object(vec![("line", Json::from(*l))])).collect();
+        request(3, "setBreakpoints", Json::object(vec![("breakpoints", Json::Array(lines))]))
+    }
+
+    fn command(command: &str) -> Json {
+        request(9, command, Json::object(vec![]))
+    }
+
+    // Handles `requests` one after the other, running the program between
+    // them like `serve` does, and returns what was sent back.
+    fn session(server: &mut DapServer<Vec<u8>>, requests: &[Json]) -> Vec<String> {
+        for request in requests {
+            server.handle(request).unwrap();
+            while server.running {
+                server.run_slice().unwrap();
+            }
+        }
+        summary(&server.out.split_off(0))
+    }
+
+    // One line per message: the command and success of a response, the
+    // event with the interesting part of its body.
+    fn summary(out: &[u8]) -> Vec<String> {
+        let mut input = Cursor::new(out);
+        let mut ret = vec![];
+        while let Some(message) = read_message(&mut input) {
+            let field = |name: &str| message.get(name).cloned().unwrap_or(Json::Null);
+            let body = field("body");
+            let detail = |name: &str| body.get(name).map(|v| v.to_string()).unwrap_or_default();
+            ret.push(match field("type").as_str() {
+                Some("response") => format!("{} {}", field("command").as_str().unwrap_or(""), field("success")),
+                _ => match field("event").as_str().unwrap_or("") {
+                    "stopped" => format!("stopped {} {}", detail("reason"), detail("text")).trim_end().to_string(),
+                    "output" => format!("output {}", detail("output")),
+                    "exited" => format!("exited {}", detail("exitCode")),
+                    event => event.to_string()
+                }
+            });
+        }
+        ret
+    }
+
+    #[test]
+    fn stops_at_breakpoints_including_the_first_instruction() {
+        let path = program("breakpoints", PROGRAM);
+        let mut server = DapServer::new(vec![]);
+        let sent = session(&mut server, &[command("initialize"), launch(&path, false), breakpoints(&[1, 3]), command("configurationDone")]);
+        assert_eq!(sent, ["initialize true", "launch true", "initialized", "setBreakpoints true", "configurationDone true", "stopped \"breakpoint\""]);
+        assert_eq!(server.vm.pc(), 0);
+        let sent = session(&mut server, &[command("continue")]);
+        assert_eq!(sent, ["continue true", "output \"2\\n\"", "stopped \"breakpoint\""]);
+        assert_eq!(server.frame().get("line"), Some(&Json::Int(3)));
+        let sent = session(&mut server, &[command("continue"), command("disconnect")]);
+        assert_eq!(sent, ["continue true", "output \"3\\n\"", "exited 7", "terminated", "disconnect true"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reports_runtime_errors_then_terminates_once() {
+        let path = program("error", "push 1\npush 0\ndiv\n");
+        let mut server = DapServer::new(vec![]);
+        let sent = session(&mut server, &[launch(&path, false), command("configurationDone")]);
+        assert_eq!(sent[2..], ["configurationDone true", "stopped \"exception\" \"instruction 2 (div): division by zero\""]);
+        let sent = session(&mut server, &[command("next"), command("continue"), command("disconnect")]);
+        assert_eq!(sent, ["next true", "exited 202", "terminated", "continue true", "disconnect true"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_second_launch_starts_afresh() {
+        let path = program("relaunch", PROGRAM);
+        let mut server = DapServer::new(vec![]);
+        session(&mut server, &[launch(&path, false), breakpoints(&[3]), command("configurationDone"), command("continue")]);
+        assert!(server.done);
+        let sent = session(&mut server, &[launch(&path, false), command("configurationDone")]);
+        assert_eq!(sent, ["launch true", "initialized", "configurationDone true", "output \"2\\n3\\n\"", "exited 7", "terminated"]);
+        let sent = session(&mut server, &[launch("missing.ekvm", false)]);
+        assert_eq!(sent, ["launch false"]);
+        assert!(server.assembly.is_none() && server.breakpoints.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn serves_a_request_stream() {
+        let path = program("serve", PROGRAM);
+        let mut input = vec![];
+        let variables = request(5, "variables", Json::object(vec![("variablesReference", Json::Int(REGISTERS_REF))]));
+        let set = request(7, "setVariable", Json::object(vec![("variablesReference", Json::Int(STACK_REF)), ("name", Json::str("[0]")), ("value", Json::str("40"))]));
+        for message in [command("initialize"), launch(&path, true), command("configurationDone"), command("next"), variables, set, command("next"), command("bogus"), command("disconnect")] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut server = DapServer::new(vec![]);
+        server.serve(Cursor::new(input)).unwrap();
+        let out = server.out.clone();
+        assert_eq!(summary(&out), [
+            "initialize true", "launch true", "initialized", "configurationDone true", "stopped \"entry\"",
+            "next true", "stopped \"step\"", "variables true", "setVariable true",
+            "next true", "output \"40\\n\"", "stopped \"step\"", "bogus false", "disconnect true", "terminated"
+        ]);
+        let messages: Vec<Json> = std::iter::from_fn({
+            let mut input = Cursor::new(out);
+            move || read_message(&mut input)
+        }).collect();
+        let registers = messages[7].get("body").and_then(|b| b.get("variables")).unwrap().to_string();
+        assert_eq!(registers, "[{\"name\":\"pc\",\"value\":\"1\",\"variablesReference\":0},{\"name\":\"stack_size\",\"value\":\"1\",\"variablesReference\":0},{\"name\":\"instruction\",\"value\":\"print\",\"variablesReference\":0}]");
+        assert_eq!(messages[12].get("message"), Some(&Json::str("Unsupported request `bogus`.")));
+        std::fs::remove_file(path).unwrap();
+    }
+}
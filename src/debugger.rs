@@ -0,0 +1,371 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use crate::vm::{DebugInfo, Instruction, StepStatus, VM, Word};
+
+const HELP: &str = "Commands:
+\tbreak <n|label> set a breakpoint at instruction n or at
+\t                a label from the debug info          (b)
+\tclear <n|label> remove the breakpoint                (d, delete)
+\tinfo            list breakpoints
+\tstep [count]    execute one (or count) instructions  (s)
+\tnext            like step, but runs backward jumps until execution
+\t                passes the current instruction       (n)
+\tcontinue        run until a breakpoint or the end    (c)
+\trecord [n|off]  keep undo history for the last n (default 10000)
+\t                instructions
+\tback [count]    step backwards, needs record          (rs)
+\trc              run backwards to the previous breakpoint
+\t                or as far as the history goes
+\tstack           print the stack                      (p, print)
+\tpc              print pc and surrounding disassembly (l, list)
+\tset <slot> <v>  overwrite stack slot (0 = bottom)
+\trestart         reload the program and start over    (r)
+\tquit            leave the debugger                   (q)";
+
+pub struct Debugger {
+    vm: VM,
+    program: Vec<Instruction>,
+    breakpoints: BTreeSet<usize>,
+    stopped: bool,
+    record_cap: usize,
+    debug: Option<DebugInfo>,
+    data: Vec<u8>
+}
+
+impl Debugger {
+    pub fn new(program: Vec<Instruction>) -> Debugger {
+        let mut vm = VM::init();
+        vm.load_program(program.clone());
+        Debugger { vm, program, breakpoints: BTreeSet::new(), stopped: false, record_cap: 0, debug: None, data: vec![] }
+    }
+    pub fn with_data(mut self, data: Vec<u8>) -> Debugger {
+        self.vm.set_data(data.clone());
+        self.data = data;
+        self
+    }
+    // Source locations and labels, as loaded from the program's debug section.
+    pub fn with_debug_info(mut self, debug: Option<DebugInfo>) -> Debugger {
+        self.vm.set_debug_info(debug.clone());
+        self.debug = debug;
+        self
+    }
+    pub fn record(&mut self, cap: usize) {
+        self.record_cap = cap;
+        self.vm.record_history(cap);
+    }
+    pub fn repl<R: BufRead>(&mut self, input: R) {
+        println!("lvm debugger, {} instructions loaded. Type `help` for commands.", self.program.len());
+        self.list(2);
+        let mut lines = input.lines();
+        loop {
+            print!("(lvm) ");
+            let _ = std::io::stdout().flush();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break
+            };
+            if !self.command(line.trim()) {
+                break;
+            }
+        }
+    }
+
+    // Runs a single debugger command, returns false once the user quits.
+    pub fn command(&mut self, line: &str) -> bool {
+        let toks: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| toks.get(i).and_then(|t| t.parse::<usize>().ok());
+        let target = |i: usize| arg(i).or_else(|| toks.get(i).and_then(|t| self.debug.as_ref().and_then(|d| d.label(t))));
+        match toks.first().copied() {
+            None => {}
+            Some("help") | Some("h") => println!("{}", HELP),
+            Some("break") | Some("b") => match target(1) {
+                Some(n) if n < self.program.len() => {
+                    self.breakpoints.insert(n);
+                    println!("Breakpoint set at {}: {}", n, self.program[n]);
+                }
+                Some(n) => println!("No instruction at {}.", n),
+                None => println!("Usage: break <instruction index|label>")
+            },
+            Some("clear") | Some("delete") | Some("d") => match target(1) {
+                Some(n) if self.breakpoints.remove(&n) => println!("Breakpoint at {} cleared.", n),
+                Some(n) => println!("No breakpoint at {}.", n),
+                None => println!("Usage: clear <instruction index|label>")
+            },
+            Some("info") => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints.");
+                }
+                for n in &self.breakpoints {
+                    println!("\t{}: {}", n, self.program[*n]);
+                }
+            }
+            Some("step") | Some("s") => {
+                let mut status = StepStatus::Running;
+                for _ in 0..arg(1).unwrap_or(1) {
+                    status = self.vm.step();
+                    if status != StepStatus::Running {
+                        break;
+                    }
+                }
+                self.report(status);
+            }
+            Some("next") | Some("n") => {
+                let from = self.vm.pc();
+                let mut status = self.vm.step();
+                while status == StepStatus::Running && self.vm.pc() <= from && !self.breakpoints.contains(&self.vm.pc()) {
+                    status = self.vm.step();
+                }
+                self.report(status);
+            }
+            Some("continue") | Some("c") => {
+                let mut status = self.vm.step();
+                while status == StepStatus::Running && !self.breakpoints.contains(&self.vm.pc()) {
+                    status = self.vm.step();
+                }
+                if status == StepStatus::Running {
+                    println!("Breakpoint at {}.", self.vm.pc());
+                }
+                self.report(status);
+            }
+            Some("record") => {
+                match toks.get(1).copied() {
+                    Some("off") => self.record(0),
+                    Some(_) if arg(1).is_none() => {
+                        println!("Usage: record [n|off]");
+                        return true;
+                    }
+                    _ => self.record(arg(1).unwrap_or(10000))
+                }
+                match self.record_cap {
+                    0 => println!("Recording off."),
+                    cap => println!("Recording the last {} instructions.", cap)
+                }
+            }
+            Some("back") | Some("rs") => {
+                if self.record_cap == 0 {
+                    println!("Not recording; use `record` first.");
+                    return true;
+                }
+                let mut moved = 0;
+                while moved < arg(1).unwrap_or(1) && self.vm.step_back() {
+                    moved += 1;
+                }
+                self.reversed(moved);
+            }
+            Some("rc") | Some("reverse-continue") => {
+                if self.record_cap == 0 {
+                    println!("Not recording; use `record` first.");
+                    return true;
+                }
+                let mut moved = 0;
+                while self.vm.step_back() {
+                    moved += 1;
+                    if self.breakpoints.contains(&self.vm.pc()) {
+                        println!("Breakpoint at {}.", self.vm.pc());
+                        break;
+                    }
+                }
+                self.reversed(moved);
+            }
+            Some("stack") | Some("print") | Some("p") => self.print_stack(),
+            Some("pc") | Some("list") | Some("l") => {
+                println!("pc = {}", self.vm.pc());
+                self.list(arg(1).unwrap_or(3));
+            }
+            Some("set") => match (arg(1), toks.get(2).and_then(|t| t.parse::<Word>().ok())) {
+                (Some(slot), Some(value)) if slot < self.vm.stack().len() => {
+                    self.vm.stack_mut()[slot] = value;
+                    self.print_stack();
+                }
+                (Some(slot), Some(_)) => println!("Stack slot {} is not in use.", slot),
+                _ => println!("Usage: set <slot> <value>")
+            },
+            Some("restart") | Some("r") => {
+                self.vm = VM::init();
+                self.vm.load_program(self.program.clone());
+                self.vm.record_history(self.record_cap);
+                self.vm.set_debug_info(self.debug.clone());
+                self.vm.set_data(self.data.clone());
+                self.stopped = false;
+                println!("Restarted.");
+                self.list(2);
+            }
+            Some("quit") | Some("q") | Some("exit") => return false,
+            Some(other) => println!("Unknown command `{}`. Type `help` for commands.", other)
+        }
+        true
+    }
+
+    fn report(&mut self, status: StepStatus) {
+        match status {
+            StepStatus::Running => self.list(0),
+            StepStatus::Halted => {
+                if !self.stopped {
+                    match self.vm.exit_code() {
+                        Some(code) => println!("Program halted with code {}.", code),
+                        None => println!("Program finished.")
+                    }
+                }
+                self.stopped = true;
+            }
+            StepStatus::Error(e) => {
+                print!("{}", e.report());
+                self.list(0);
+            }
+        }
+    }
+
+    fn reversed(&mut self, moved: usize) {
+        if moved == 0 {
+            println!("No more history.");
+        } else if self.vm.history_len() == 0 {
+            println!("Reached the start of the recorded history.");
+        }
+        self.stopped = false;
+        self.list(0);
+    }
+
+    fn print_stack(&self) {
+        let stack = self.vm.stack();
+        if stack.is_empty() {
+            println!("\t| EMPTY |");
+        }
+        for (n, w) in stack.iter().enumerate() {
+            println!("\t| {} : {}", n, w);
+        }
+    }
+
+    fn list(&self, context: usize) {
+        let pc = self.vm.pc();
+        let from = pc.saturating_sub(context);
+        let to = (pc + context + 1).min(self.program.len());
+        for n in from..to {
+            let marker = if n == pc { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&n) { "*" } else { " " };
+            if let Some(debug) = &self.debug {
+                for label in debug.labels_at(n) {
+                    println!("          {}:", label);
+                }
+            }
+            match self.vm.location(n) {
+                Some(loc) => println!("{}{} {:>4}: {:<12} ; {}:{}", marker, bp, n, self.program[n].to_string(), loc.file, loc.line),
+                None => println!("{}{} {:>4}: {}", marker, bp, n, self.program[n])
+            }
+        }
+        if pc >= self.program.len() {
+            println!("=>  {:>4}: <end of program>", pc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Counts 3 down to 0, then halts with 42.
+    const PROGRAM: &str = "push 3\nloop:\npush 1\nsub\ndup 0\npush 0\nneq\njnz loop\ndone:\npush 42\nhalt\n";
+
+    fn debugger() -> Debugger {
+        let assembly = assemble(PROGRAM).unwrap();
+        let debug = assembly.debug_info("count.vm");
+        let mut debugger = Debugger::new(assembly.program).with_debug_info(Some(debug));
+        debugger.vm.set_output(Box::new(std::io::sink()));
+        debugger
+    }
+
+    #[test]
+    fn breakpoints_by_index_and_label() {
+        let mut d = debugger();
+        assert!(d.command("break 3"));
+        assert!(d.command("b done"));
+        assert!(d.command("break 99"));
+        assert!(d.command("break nowhere"));
+        assert_eq!(d.breakpoints, [3, 7].into());
+        assert!(d.command("clear done"));
+        assert!(d.command("d 5"));
+        assert_eq!(d.breakpoints, [3].into());
+        assert!(d.command("delete loop"));
+        assert!(d.command("clear 3"));
+        assert!(d.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn stepping_and_continuing() {
+        let mut d = debugger();
+        d.command("step");
+        d.command("s 3");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (4, &[2, 2][..]));
+        d.command("break 1");
+        d.command("continue");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (1, &[2][..]));
+        // `next` at the loop's jump runs the rest of the loop.
+        d.command("clear 1");
+        d.command("s 5");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (6, &[1, 1][..]));
+        d.command("next");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (7, &[0, 0][..]));
+        // Unless a breakpoint comes first.
+        d.command("restart");
+        d.command("break 2");
+        d.command("s 6");
+        d.command("n");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (2, &[2, 1][..]));
+        d.command("clear 2");
+        d.command("c");
+        assert_eq!(d.vm.exit_code(), Some(42));
+        assert!(d.stopped);
+        // Stepping a halted program does nothing.
+        d.command("step");
+        assert_eq!(d.vm.exit_code(), Some(42));
+    }
+
+    #[test]
+    fn recording_and_stepping_back() {
+        let mut d = debugger();
+        d.command("back");
+        assert_eq!(d.record_cap, 0);
+        d.command("record bogus");
+        assert_eq!(d.record_cap, 0);
+        d.command("record 100");
+        d.command("break 3");
+        d.command("s 10");
+        let (pc, stack) = (d.vm.pc(), d.vm.stack().to_vec());
+        d.command("back 2");
+        d.command("s 2");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (pc, &stack[..]));
+        d.command("rc");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (3, &[1][..]));
+        d.command("rc");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (3, &[2][..]));
+        // Back to the start of the history.
+        d.command("rc");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (0, &[][..]));
+        d.command("record off");
+        assert_eq!((d.record_cap, d.vm.history_len()), (0, 0));
+    }
+
+    #[test]
+    fn editing_restarting_and_quitting() {
+        let mut d = debugger();
+        d.command("s 2");
+        d.command("set 0 10");
+        d.command("set 2 10");
+        d.command("set 0 x");
+        assert_eq!(d.vm.stack(), &[10, 1]);
+        d.command("s");
+        assert_eq!(d.vm.stack(), &[9]);
+        d.command("break done");
+        d.command("restart");
+        assert_eq!((d.vm.pc(), d.vm.stack()), (0, &[][..]));
+        assert_eq!(d.breakpoints, [7].into());
+        d.command("c");
+        assert_eq!(d.vm.pc(), 7);
+        assert!(d.command("stack"));
+        assert!(d.command("list 1"));
+        assert!(d.command("frobnicate"));
+        assert!(d.command(""));
+        assert!(!d.command("quit"));
+        assert!(!d.command("q"));
+    }
+}
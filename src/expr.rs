@@ -0,0 +1,410 @@
+use std::fmt;
+use std::num::IntErrorKind;
+use crate::vm::Word;
+
+// Operand expressions of the assembler. Precedence follows C, loosest first:
+// `|`, `&`, `<< >>`, `+ -`, `* / %`. Everything is evaluated on unsigned
+// 64 bit words and overflow is an error rather than wrapping; only negative
+// literals such as `-1` are stored as two's complement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem
+}
+
+impl BinOp {
+    fn level(self) -> usize {
+        match self {
+            BinOp::Or => 0,
+            BinOp::And => 1,
+            BinOp::Shl | BinOp::Shr => 2,
+            BinOp::Add | BinOp::Sub => 3,
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 4
+        }
+    }
+    pub fn apply(self, x: Word, y: Word) -> Result<Word, String> {
+        let overflow = || format!("Overflow in {} {} {}", x, self, y);
+        match self {
+            BinOp::Or => Ok(x | y),
+            BinOp::And => Ok(x & y),
+            BinOp::Shl | BinOp::Shr if y >= 64 => Err(format!("Shift by {} bits", y)),
+            BinOp::Shl => Some(x << y).filter(|r| r >> y == x).ok_or_else(overflow),
+            BinOp::Shr => Ok(x >> y),
+            BinOp::Add => x.checked_add(y).ok_or_else(overflow),
+            BinOp::Sub => x.checked_sub(y).ok_or_else(overflow),
+            BinOp::Mul => x.checked_mul(y).ok_or_else(overflow),
+            BinOp::Div | BinOp::Rem if y == 0 => Err(format!("Division by zero in {} {} {}", x, self, y)),
+            BinOp::Div => Ok(x / y),
+            BinOp::Rem => Ok(x % y)
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            BinOp::Or => "|",
+            BinOp::And => "&",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(Word),
+    Name(String),
+    Binary(BinOp, Box<Expr>, Box<Expr>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Num(&'a str),
+    Char(Word),
+    Name(&'a str),
+    Op(BinOp),
+    Open,
+    Close
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+// Reads one, possibly escaped, character at the start of `text`, returning
+// its value and length in bytes. Supports `\n \t \r \0 \\ \' \"` and `\xNN`.
+fn unescape(text: &str) -> Option<(Word, usize)> {
+    let mut chars = text.chars();
+    let value = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n' as Word,
+            't' => '\t' as Word,
+            'r' => '\r' as Word,
+            '0' => 0,
+            c @ ('\\' | '\'' | '"') => c as Word,
+            'x' => {
+                let hex = text.get(2..4).filter(|h| h.chars().all(|c| c.is_ascii_hexdigit()))?;
+                return Some((Word::from_str_radix(hex, 16).ok()?, 4));
+            }
+            _ => return None
+        },
+        c => c as Word
+    };
+    Some((value, text.len() - chars.as_str().len()))
+}
+
+// Reads a character literal at the start of `text`, returning its value and
+// length in bytes.
+fn char_literal(text: &str) -> Result<(Word, usize), String> {
+    let invalid = || format!("Invalid character literal `{}`", text.split_whitespace().next().unwrap_or(text));
+    if text[1..].starts_with('\'') {
+        return Err(invalid());
+    }
+    let (value, len) = unescape(&text[1..]).ok_or_else(invalid)?;
+    match text[1 + len..].starts_with('\'') {
+        true => Ok((value, len + 2)),
+        false => Err(invalid())
+    }
+}
+
+// The bytes of a double quoted string, encoded as UTF-8 except that `\xNN`
+// escapes stand for single bytes. There is no terminator.
+pub fn string_literal(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    let invalid = || format!("Invalid string literal `{}`", text);
+    let mut rest = text.strip_prefix('"').ok_or_else(invalid)?;
+    let mut ret = vec![];
+    loop {
+        if let Some(after) = rest.strip_prefix('"') {
+            return match after.trim().is_empty() {
+                true => Ok(ret),
+                false => Err(format!("Unexpected `{}` after the string", after.trim()))
+            };
+        }
+        let (value, len) = unescape(rest).ok_or_else(invalid)?;
+        match rest.starts_with("\\x") {
+            true => ret.push(value as u8),
+            false => {
+                let c = char::from_u32(value as u32).ok_or_else(invalid)?;
+                ret.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+        }
+        rest = &rest[len..];
+    }
+}
+
+// Splits an expression into tokens with their byte offsets.
+fn lex(text: &str) -> Result<Vec<(usize, Token<'_>)>, String> {
+    let mut ret = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let run = |pred: fn(char) -> bool| text[i..].find(|c| !pred(c)).map(|e| i + e).unwrap_or(text.len());
+        let (token, end) = match c {
+            c if c.is_whitespace() => continue,
+            c if c.is_ascii_digit() => (Token::Num(&text[i..run(is_name_char)]), run(is_name_char)),
+            c if is_name_start(c) => (Token::Name(&text[i..run(is_name_char)]), run(is_name_char)),
+            '\'' => {
+                let (value, len) = char_literal(&text[i..])?;
+                (Token::Char(value), i + len)
+            }
+            '(' => (Token::Open, i + 1),
+            ')' => (Token::Close, i + 1),
+            '+' => (Token::Op(BinOp::Add), i + 1),
+            '-' => (Token::Op(BinOp::Sub), i + 1),
+            '*' => (Token::Op(BinOp::Mul), i + 1),
+            '/' => (Token::Op(BinOp::Div), i + 1),
+            '%' => (Token::Op(BinOp::Rem), i + 1),
+            '&' => (Token::Op(BinOp::And), i + 1),
+            '|' => (Token::Op(BinOp::Or), i + 1),
+            '<' | '>' if text[i + 1..].starts_with(c) => (Token::Op(if c == '<' { BinOp::Shl } else { BinOp::Shr }), i + 2),
+            c => return Err(format!("Unexpected `{}`", c))
+        };
+        // Skip the rest of multi character tokens.
+        while chars.peek().is_some_and(|(j, _c)| *j < end) {
+            chars.next();
+        }
+        ret.push((i, token));
+    }
+    Ok(ret)
+}
+
+// Decimal, `0x` hexadecimal, `0b` binary or `0o` octal, with optional `_`
+// separators between digits.
+pub fn parse_number(text: &str) -> Result<Word, String> {
+    let (digits, radix) = match text.get(..2).map(|p| p.to_ascii_lowercase()).as_deref() {
+        Some("0x") => (&text[2..], 16),
+        Some("0b") => (&text[2..], 2),
+        Some("0o") => (&text[2..], 8),
+        _ => (text, 10)
+    };
+    let valid = !digits.is_empty() && !digits.starts_with('_') && !digits.ends_with('_')
+        && digits.chars().all(|c| c == '_' || c.is_digit(radix));
+    if !valid {
+        return Err(format!("Invalid number `{}`", text));
+    }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    Word::from_str_radix(&digits, radix).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => format!("Number `{}` does not fit in 64 bits", text),
+        _ => format!("Invalid number `{}`", text)
+    })
+}
+
+// The names an expression refers to, with their byte offsets. Text that does
+// not lex yields none.
+pub fn names(text: &str) -> Vec<(usize, &str)> {
+    lex(text).unwrap_or_default().into_iter()
+        .filter_map(|(i, t)| match t {
+            Token::Name(name) => Some((i, name)),
+            _ => None
+        })
+        .collect()
+}
+
+// Reprints an expression with single spaces around binary operators, none
+// inside parentheses or after a unary minus, and lowercase number prefixes
+// and hex digits. Names and character literals are kept as written.
+pub fn format(text: &str) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut prev: Option<Token> = None;
+    let mut unary = false;
+    for (i, token) in lex(text)? {
+        let space = match (&prev, &token) {
+            (None, _) | (Some(Token::Open), _) | (_, Token::Close) => false,
+            (Some(Token::Op(_)), _) => !unary,
+            _ => true
+        };
+        unary = matches!(token, Token::Op(BinOp::Sub)) && matches!(prev, None | Some(Token::Op(_)) | Some(Token::Open));
+        if space {
+            ret.push(' ');
+        }
+        match &token {
+            Token::Num(n) if n.len() > 1 && n[1..].starts_with(['x', 'X', 'b', 'B', 'o', 'O']) => ret += &n.to_ascii_lowercase(),
+            Token::Num(n) | Token::Name(n) => ret += n,
+            Token::Char(_c) => ret += &text[i..i + char_literal(&text[i..])?.1],
+            Token::Op(op) => ret += &op.to_string(),
+            Token::Open => ret.push('('),
+            Token::Close => ret.push(')')
+        }
+        prev = Some(token);
+    }
+    Ok(ret)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token<'a>)>,
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos).map(|(_i, t)| t)
+    }
+    // Parses operators of `level` and tighter.
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let mut lhs = self.primary()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            if op.level() < level {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(op.level() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Expr::Num(parse_number(n)?)),
+            Some(Token::Char(c)) => Ok(Expr::Num(c)),
+            // A minus in front of a literal makes a negative literal.
+            Some(Token::Op(BinOp::Sub)) if matches!(self.peek(), Some(Token::Num(_)) | Some(Token::Char(_))) => {
+                let value = match self.peek().cloned() {
+                    Some(Token::Num(n)) => parse_number(n)?,
+                    Some(Token::Char(c)) => c,
+                    _ => 0
+                };
+                self.pos += 1;
+                if value > 1 << 63 {
+                    return Err(format!("-{} does not fit in 64 bits", value));
+                }
+                Ok(Expr::Num(value.wrapping_neg()))
+            }
+            Some(Token::Name(name)) => Ok(Expr::Name(name.to_string())),
+            Some(Token::Open) => {
+                let inner = self.binary(0)?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("Missing `)`".to_string())
+                }
+            }
+            Some(Token::Close) => Err("Unexpected `)`".to_string()),
+            Some(Token::Op(op)) => Err(format!("Expected a value before `{}`", op)),
+            None => Err("Expected a value".to_string())
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: lex(text)?, pos: 0 };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some((i, _t)) => Err(format!("Unexpected `{}` after the operand", text[*i..].trim_end()))
+        }
+    }
+
+    // `lookup` resolves names and `apply` combines values, usually with
+    // `BinOp::apply`; values can be richer than words, such as addresses the
+    // linker still has to relocate.
+    pub fn eval<V: From<Word>, E>(&self, lookup: &mut dyn FnMut(&str) -> Result<V, E>, apply: &dyn Fn(BinOp, V, V) -> Result<V, E>) -> Result<V, E> {
+        match self {
+            Expr::Num(n) => Ok(V::from(*n)),
+            Expr::Name(name) => lookup(name),
+            Expr::Binary(op, x, y) => {
+                let x = x.eval(lookup, apply)?;
+                let y = y.eval(lookup, apply)?;
+                apply(*op, x, y)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<Word, String> {
+        let names = [("two", 2), ("ten", 10)];
+        Expr::parse(text)?.eval(
+            &mut |name| names.iter().find(|(n, _v)| *n == name).map(|(_n, v)| *v).ok_or(format!("Undefined name `{}`", name)),
+            &|op, x, y| op.apply(x, y)
+        )
+    }
+
+    #[test]
+    fn precedence_follows_c() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("100 / 10 / 5"), Ok(2));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 & 3 | 8"), Ok(10));
+        assert_eq!(eval("1 | 2 & 0"), Ok(1));
+        assert_eq!(eval("ten % 4 * two"), Ok(4));
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert_eq!(eval("0xffffffffffffffff + 1"), Err("Overflow in 18446744073709551615 + 1".to_string()));
+        assert_eq!(eval("1 - 2"), Err("Overflow in 1 - 2".to_string()));
+        assert!(eval("0x100000000 * 0x100000000").is_err());
+        assert!(eval("1 << 64").is_err());
+        assert!(eval("3 << 63").is_err());
+        assert_eq!(eval("ten / 0"), Err("Division by zero in 10 / 0".to_string()));
+    }
+
+    #[test]
+    fn undefined_names_and_syntax_errors() {
+        assert_eq!(eval("two + three"), Err("Undefined name `three`".to_string()));
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("1 $ 2").is_err());
+    }
+
+    #[test]
+    fn number_and_character_literals() {
+        assert_eq!(eval("0x1F"), Ok(31));
+        assert_eq!(eval("0b1010"), Ok(10));
+        assert_eq!(eval("0o17"), Ok(15));
+        assert_eq!(eval("1_000_000"), Ok(1_000_000));
+        assert_eq!(eval("'A'"), Ok(65));
+        assert_eq!(eval("'\\n'"), Ok(10));
+        assert_eq!(eval("'\\x7f'"), Ok(127));
+        assert_eq!(eval("'\\''"), Ok(39));
+        assert!(eval("0x").is_err());
+        assert!(eval("0b102").is_err());
+        assert_eq!(parse_number("18446744073709551616"), Err("Number `18446744073709551616` does not fit in 64 bits".to_string()));
+    }
+
+    #[test]
+    fn negative_literals_are_twos_complement() {
+        assert_eq!(eval("-1"), Ok(Word::MAX));
+        assert_eq!(eval("-128"), Ok(Word::MAX - 127));
+        assert_eq!(eval("-0x10"), Ok(0u64.wrapping_sub(16)));
+        assert_eq!(eval("(-1)"), Ok(Word::MAX));
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(string_literal("\"hi\\n\""), Ok(b"hi\n".to_vec()));
+        assert_eq!(string_literal("\"\\xff\\x00\""), Ok(vec![0xff, 0]));
+        assert_eq!(string_literal("\"é\""), Ok("é".as_bytes().to_vec()));
+        assert!(string_literal("\"open").is_err());
+        assert!(string_literal("\"a\" b").is_err());
+    }
+}
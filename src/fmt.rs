@@ -0,0 +1,245 @@
+use crate::asm::{after_token, is_mnemonic, split_args, split_comment, split_items, tokens};
+use crate::expr;
+
+// Reprints .vm sources in one canonical layout:
+//
+//  - labels on a line of their own in the first column,
+//  - directives that structure the file (`.const`, `.include`, `.macro`,
+//    `.endm`, `.data`, `.text`, `.proc`, `.endp`, `.export`, `.import`) in
+//    the first column too,
+//  - instructions, data directives and macro invocations indented by
+//    INDENT, with the operands of instructions and data directives starting
+//    in one column per file, and by another INDENT inside every `.if`,
+//    `.while` and `.loop` block,
+//  - lowercase mnemonics and directives, operand expressions spaced by
+//    `expr::format`, list items separated by `, `,
+//  - trailing comments of consecutive lines aligned, comments on a line of
+//    their own indented like code if they were indented at all,
+//  - no trailing whitespace and at most one blank line in a row.
+//
+// Formatting works line by line on the text, so it does not need includes
+// or macros to be resolvable.
+const INDENT: &str = "    ";
+
+const TOP_LEVEL: &[&str] = &[".const", ".include", ".macro", ".endm", ".data", ".text", ".proc", ".endp", ".export", ".import"];
+const DIRECTIVES: &[&str] = &[".word", ".bytes", ".string"];
+const OPENERS: &[&str] = &[".if", ".while", ".loop"];
+const CLOSERS: &[&str] = &[".else", ".endif", ".endwhile", ".endloop"];
+
+enum Line {
+    Blank,
+    Label(String, Option<String>),
+    // Op, operand and trailing comment; indented unless `top` is set, by
+    // one more INDENT per enclosing block.
+    Code { top: bool, depth: usize, op: String, operand: String, comment: Option<String> },
+    Comment { indented: bool, depth: usize, text: String }
+}
+
+// Collapses runs of whitespace outside of literals, for operands that are
+// not expressions such as `\param` references in macro bodies.
+fn collapse(text: &str) -> String {
+    let mut ret = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.trim().chars() {
+        match quote {
+            Some(q) => {
+                if !escaped && c == q {
+                    quote = None;
+                }
+                escaped = !escaped && c == '\\';
+            }
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c.is_whitespace() && ret.ends_with(' ') => continue,
+            None if c.is_whitespace() => {
+                ret.push(' ');
+                continue;
+            }
+            None => {}
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+fn expression(text: &str) -> String {
+    expr::format(text).unwrap_or_else(|_e| collapse(text))
+}
+
+fn list(items: Vec<String>) -> String {
+    items.iter().map(|i| expression(i)).collect::<Vec<_>>().join(", ")
+}
+
+fn operand(op: &str, rest: &str) -> String {
+    let rest = rest.trim();
+    match op {
+        _ if rest.is_empty() => String::new(),
+        ".const" => match rest.split_once(char::is_whitespace) {
+            Some((name, value)) => format!("{} {}", name, expression(value)),
+            None => rest.to_string()
+        },
+        ".macro" => {
+            let words = split_args(rest);
+            match words.split_first() {
+                Some((name, params)) if !params.is_empty() => format!("{} {}", name, params.join(", ")),
+                _ => collapse(rest)
+            }
+        }
+        ".word" | ".bytes" | ".export" | ".import" => list(split_items(rest)),
+        ".string" | ".include" => rest.to_string(),
+        // The stack effect is spaced like `( a b -- c )`.
+        ".proc" => match rest.split_once(char::is_whitespace) {
+            Some((name, effect)) if effect.trim().starts_with('(') && effect.trim().ends_with(')') => {
+                let inner = effect.trim()[1..effect.trim().len() - 1].replace("--", " -- ");
+                let words: Vec<&str> = inner.split_whitespace().collect();
+                format!("{} ( {} )", name, words.join(" ")).replace("(  )", "( )")
+            }
+            _ => collapse(rest)
+        },
+        _ if is_mnemonic(op) || op == ".loop" => expression(rest),
+        // A macro invocation.
+        _ => list(split_args(rest))
+    }
+}
+
+fn parse(source: &str) -> Vec<Line> {
+    let mut ret = vec![];
+    let mut depth = 0;
+    for text in source.lines() {
+        let (code, comment) = split_comment(text);
+        let comment = comment.map(|c| c.trim_end().to_string());
+        let mut toks = tokens(code);
+        if toks.is_empty() {
+            ret.push(match comment {
+                Some(text) => Line::Comment { indented: !code.is_empty(), depth, text },
+                None => Line::Blank
+            });
+            continue;
+        }
+        if let Some(label) = toks.first().and_then(|(_col, t)| t.strip_suffix(':')) {
+            ret.push(Line::Label(label.to_string(), if toks.len() == 1 { comment.clone() } else { None }));
+            toks.remove(0);
+            if toks.is_empty() {
+                continue;
+            }
+        }
+        let first = toks[0].1;
+        let lower = first.to_ascii_lowercase();
+        let known = [TOP_LEVEL, DIRECTIVES, OPENERS, CLOSERS].iter().any(|list| list.contains(&lower.as_str()));
+        let op = match is_mnemonic(&lower) || known {
+            true => lower,
+            false => first.to_string()
+        };
+        if CLOSERS.contains(&op.as_str()) {
+            depth = depth.saturating_sub(1);
+        }
+        let operand = operand(&op, after_token(code, toks[0]));
+        let line_depth = depth;
+        if OPENERS.contains(&op.as_str()) || op == ".else" {
+            depth += 1;
+        }
+        ret.push(Line::Code { top: TOP_LEVEL.contains(&op.as_str()), depth: line_depth, op, operand, comment });
+    }
+    ret
+}
+
+// The canonical form of `source`.
+pub fn format(source: &str) -> String {
+    let lines = parse(source);
+    let width = lines.iter()
+        .filter_map(|l| match l {
+            Line::Code { top: false, op, operand, .. } if !operand.is_empty() && (is_mnemonic(op) || DIRECTIVES.contains(&op.as_str())) => Some(op.chars().count()),
+            _ => None
+        })
+        .max()
+        .unwrap_or(0);
+    // The code of every output line and its trailing comment.
+    let mut out: Vec<(String, Option<String>)> = vec![];
+    for line in lines {
+        match line {
+            Line::Blank if out.last().is_none_or(|(code, comment)| code.is_empty() && comment.is_none()) => {}
+            Line::Blank => out.push((String::new(), None)),
+            Line::Label(name, comment) => out.push((format!("{}:", name), comment)),
+            Line::Comment { indented, depth, text } => out.push((if indented { INDENT.repeat(depth + 1) } else { String::new() }, Some(text))),
+            Line::Code { top, depth, op, operand, comment } => {
+                let code = match (top, operand.is_empty()) {
+                    (_, true) => op,
+                    (true, false) => format!("{} {}", op, operand),
+                    (false, false) if OPENERS.contains(&op.as_str()) => format!("{} {}", op, operand),
+                    (false, false) => format!("{:<w$} {}", op, operand, w = width)
+                };
+                out.push((if top { code } else { format!("{}{}", INDENT.repeat(depth + 1), code) }, comment));
+            }
+        }
+    }
+    while out.last().is_some_and(|(code, comment)| code.is_empty() && comment.is_none()) {
+        out.pop();
+    }
+    // Trailing comments of a run of consecutive lines share a column.
+    let trailing: Vec<bool> = out.iter().map(|(code, comment)| comment.is_some() && !code.trim().is_empty()).collect();
+    let mut ret = String::new();
+    let mut column = 0;
+    for (i, (code, comment)) in out.iter().enumerate() {
+        if trailing[i] && (i == 0 || !trailing[i - 1]) {
+            column = (i..out.len()).take_while(|j| trailing[*j]).map(|j| out[j].0.chars().count()).max().unwrap_or(0);
+        }
+        match comment {
+            Some(comment) if trailing[i] => ret += &format!("{:<w$} {}\n", code, comment, w = column),
+            Some(comment) => ret += &format!("{}{}\n", code, comment),
+            None => ret += &format!("{}\n", code)
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const MESSY: &str = "; Counts down\n.const N   (3+1)*2 ;start\n\n\n.macro countdown n,step\nagain: push \\step\nsub\n.endm\nstart: push N   ; first\n  dup 0\n    countdown 0 , 1\n  .if\n print\n  .endif\n  halt\n.data\nmsg: .string \"a;b\" ; kept\n.word 1,2 ,0X1F\n\n\n";
+
+    #[test]
+    fn formats_into_the_canonical_layout() {
+        let expected = "\
+; Counts down
+.const N (3 + 1) * 2 ;start
+
+.macro countdown n, step
+again:
+    push    \\step
+    sub
+.endm
+start:
+    push    N ; first
+    dup     0
+    countdown 0, 1
+    .if
+        print
+    .endif
+    halt
+.data
+msg:
+    .string \"a;b\" ; kept
+    .word   1, 2, 0x1f
+";
+        assert_eq!(format(MESSY), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format(MESSY);
+        assert_eq!(format(&once), once);
+        let proc = ".proc  sq   (n--n2)\nDUP 0 ; copy\nmul ; square\n.endp\n";
+        let once = format(proc);
+        assert_eq!(once, ".proc sq ( n -- n2 )\n    dup 0 ; copy\n    mul   ; square\n.endp\n");
+        assert_eq!(format(&once), once);
+    }
+
+    #[test]
+    fn formatting_keeps_the_program() {
+        let before = assemble(MESSY).unwrap();
+        let after = assemble(&format(MESSY)).unwrap();
+        assert_eq!((before.program, before.data, before.labels), (after.program, after.data, after.labels));
+    }
+}
@@ -0,0 +1,25 @@
+pub mod vm;
+pub mod asm;
+pub mod obj;
+pub mod expr;
+pub mod verify;
+pub mod observer;
+pub mod trace;
+pub mod debugger;
+pub mod dap;
+pub mod lsp;
+pub mod fmt;
+pub mod repl;
+pub mod profile;
+pub mod coverage;
+pub mod listing;
+pub mod replay;
+#[path ="./utils/list.rs"]
+pub mod list;
+#[path ="./utils/string.rs"]
+pub mod string;
+#[path ="./utils/io.rs"]
+#[allow(clippy::module_inception)]
+pub mod io;
+#[path ="./utils/json.rs"]
+pub mod json;
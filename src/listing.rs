@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use crate::asm::Assembly;
+use crate::obj::Place;
+
+// An assembler listing: every line of `source` (read from `path`) with the
+// instructions assembled from it, one row each:
+//     <line> <index> <encoding> <operand> <text>
+// The encoding is what `get_byte_code` writes for the instruction, the
+// operand its resolved value. Instructions from included files are listed
+// where they end up in the program, under a `--- <file>` row; lines that
+// expand to several instructions show their text on the first one only.
+// Relocations of object files follow the text as a comment. A table of
+// labels, data labels, constants and imports ends the listing.
+pub fn listing(assembly: &Assembly, source: &str, path: &str) -> String {
+    let top: Vec<&str> = source.lines().collect();
+    let mut included: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut ret = format!("{:>5}  {:>5}  {:<72}  {:>20}  {}\n", "line", "index", "encoding", "operand", "source");
+    // The top level lines listed so far, the file of the last row and the
+    // place of the last instruction.
+    let mut next = 1;
+    let mut file = None;
+    let mut last = None;
+    let row = |line: usize, index: String, encoding: &str, operand: String, text: &str| {
+        format!("{:>5}  {:>5}  {:<72}  {:>20}  {}", line, index, encoding, operand, text).trim_end().to_string() + "\n"
+    };
+    for (i, inst) in assembly.program.iter().enumerate() {
+        let (line, inst_file) = (assembly.lines[i], assembly.files[i].as_deref());
+        // Top level lines before this instruction, or up to the `.include`
+        // that brought in its file.
+        let until = match inst_file {
+            None => line,
+            Some(_f) if file.is_none() => (next..=top.len()).find(|l| top[l - 1].trim_start().starts_with(".include")).map_or(next, |l| l + 1),
+            Some(_f) => next
+        };
+        while next < until && next <= top.len() {
+            if file.is_some() {
+                ret += &format!("--- {}\n", path);
+                file = None;
+            }
+            ret += &row(next, String::new(), "", String::new(), top[next - 1]);
+            next += 1;
+        }
+        if inst_file != file {
+            ret += &format!("--- {}\n", inst_file.unwrap_or(path));
+            file = inst_file;
+        }
+        let text = match (last == Some((inst_file, line)), inst_file) {
+            (true, _) => String::new(),
+            (false, None) => {
+                next = next.max(line + 1);
+                top.get(line - 1).unwrap_or(&"").to_string()
+            }
+            (false, Some(f)) => {
+                let lines = included.entry(f).or_insert_with(|| std::fs::read_to_string(f).map(|t| t.lines().map(|l| l.to_string()).collect()).unwrap_or_default());
+                lines.get(line - 1).cloned().unwrap_or_default()
+            }
+        };
+        let relocs: Vec<String> = assembly.relocs.iter()
+            .filter(|r| r.place == Place::Code(i))
+            .map(|r| match r.factor {
+                1 => format!("{}", r.target),
+                factor => format!("{} * {}", factor, r.target)
+            })
+            .collect();
+        let text = match (relocs.is_empty(), text.is_empty()) {
+            (true, _) => text,
+            (false, true) => format!("; + {}", relocs.join(" + ")),
+            (false, false) => format!("{} ; + {}", text, relocs.join(" + "))
+        };
+        let operand = inst.operand().map(|o| o.to_string()).unwrap_or_default();
+        ret += &row(line, i.to_string(), &inst.encode(), operand, &text);
+        last = Some((inst_file, line));
+    }
+    if file.is_some() && next <= top.len() {
+        ret += &format!("--- {}\n", path);
+    }
+    for (i, text) in top.iter().enumerate().skip(next - 1) {
+        ret += &row(i + 1, String::new(), "", String::new(), text);
+    }
+    ret += "\nSymbols:\n";
+    let mut symbols: Vec<(&str, &str, String)> = vec![];
+    symbols.extend(assembly.labels.iter().map(|(name, i)| (name.as_str(), "label", i.to_string())));
+    symbols.extend(assembly.data_labels.iter().map(|(name, o)| (name.as_str(), "data", o.to_string())));
+    symbols.extend(assembly.consts.iter().map(|(name, v)| (name.as_str(), "const", v.to_string())));
+    symbols.extend(assembly.imports.iter().map(|name| (name.as_str(), "import", String::new())));
+    symbols.sort();
+    let width = symbols.iter().map(|(name, _kind, _value)| name.chars().count()).max().unwrap_or(0);
+    for (name, kind, value) in symbols {
+        let exported = if assembly.exports.iter().any(|e| e == name) { " exported" } else { "" };
+        ret += format!("    {:<w$}  {:<6}  {}{}", name, kind, value, exported, w = width).trim_end();
+        ret += "\n";
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, assemble_object};
+
+    // The rows of a listing split into their columns.
+    fn rows(listing: &str) -> Vec<Vec<&str>> {
+        listing.lines().map(|l| l.split_whitespace().collect()).collect()
+    }
+
+    #[test]
+    fn lists_every_line_with_its_instructions() {
+        let source = ".const N 2\nstart: push N ; two\n    dup 0\n\n    jnz start\n.loop 2\n.endloop\n";
+        let listing = listing(&assemble(source).unwrap(), source, "t.vm");
+        let rows = rows(&listing);
+        assert_eq!(rows[1], ["1", ".const", "N", "2"]);
+        assert_eq!(rows[2][..4], ["2", "0", "000000000000000000000000000000000000000000000000000000000000000000000010", "2"]);
+        assert_eq!(rows[2][4..], ["start:", "push", "N", ";", "two"]);
+        assert_eq!(rows[3][..2], ["3", "1"]);
+        assert_eq!(rows[4], ["4"]);
+        assert_eq!(rows[5][..2], ["5", "2"]);
+        // `.loop 2` lowers to several instructions; only the first shows the text.
+        assert_eq!(rows[6][..2], ["6", "3"]);
+        assert_eq!(rows[6].last(), Some(&"2"));
+        assert_eq!(rows[7].len(), 4);
+        assert!(listing.ends_with("\n    N             const   2\n    start         label   0\n"));
+        assert!(listing.contains("\nSymbols:\n    .loop.1.body  label   12\n"));
+    }
+
+    #[test]
+    fn notes_relocations_and_imports() {
+        let source = ".import f\n.export start\nstart: jmp f\n    push start + 1\n";
+        let listing = listing(&assemble_object(source, None, &[]).unwrap(), source, "t.vm");
+        let rows = rows(&listing);
+        assert_eq!(rows[3][4..], ["start:", "jmp", "f", ";", "+", "symbol", "f"]);
+        assert_eq!(rows[4][3..], ["1", "push", "start", "+", "1", ";", "+", "code"]);
+        assert!(listing.ends_with("\nSymbols:\n    f      import\n    start  label   0 exported\n"));
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use crate::asm::{after_token, assemble_object, is_label_name, split_comment, tokens, Assembly, AsmError};
+use crate::dap::{read_message, write_message};
+use crate::expr::names;
+use crate::json::Json;
+
+// Mnemonic, whether it takes an operand, its stack effect and what it does.
+// Shown on hover and in completions.
+const MNEMONICS: &[(&str, bool, &str, &str)] = &[
+    ("push", true, "( -- n )", "Pushes the operand."),
+    ("add", false, "( a b -- a+b )", "Adds the two top values."),
+    ("sub", false, "( a b -- a-b )", "Subtracts the top value from the one below it."),
+    ("mul", false, "( a b -- a*b )", "Multiplies the two top values."),
+    ("div", false, "( a b -- a/b )", "Divides the second value by the top one. Fails if either is 0."),
+    ("dup", true, "( xn .. x0 -- xn .. x0 xn )", "Copies the value n slots below the top onto the stack; `dup 0` duplicates the top."),
+    ("dump", false, "( -- )", "Prints the whole stack."),
+    ("print", false, "( a -- a )", "Prints the top value without popping it."),
+    ("jmp", true, "( -- )", "Jumps to the operand."),
+    ("eq", false, "( a b -- a==b )", "Pushes 1 if the two top values are equal, 0 otherwise."),
+    ("neq", false, "( a b -- a!=b )", "Pushes 1 if the two top values differ, 0 otherwise."),
+    ("jnz", true, "( 1 -- ) or ( c -- c )", "Pops the top value and jumps to the operand if it is 1; otherwise leaves it and falls through."),
+    ("halt", false, "( code -- )", "Stops the program with the top value as exit code."),
+    ("blind", false, "( -- )", "Does nothing."),
+    ("loadb", false, "( addr -- byte )", "Replaces the top value with the byte at that offset of the data segment."),
+    ("loadw", false, "( addr -- word )", "Replaces the top value with the 8 byte little endian word at that offset of the data segment.")
+];
+
+// LSP diagnostic severity and completion / symbol kinds.
+const SEVERITY_ERROR: i128 = 1;
+const COMPLETION_KEYWORD: i128 = 14;
+const COMPLETION_REFERENCE: i128 = 18;
+const SYMBOL_FUNCTION: i128 = 12;
+
+// A label or constant definition, or a use of one. Lines and characters are 0 based, as LSP counts
+// them; sources are assumed to be ASCII so characters and UTF-16 units agree.
+struct Symbol<'a> {
+    name: &'a str,
+    line: usize,
+    start: usize,
+    definition: bool
+}
+
+impl Symbol<'_> {
+    fn range(&self) -> Json {
+        range(self.line, self.start, self.start + self.name.chars().count())
+    }
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    Json::object(vec![("start", position(line, start)), ("end", position(line, end))])
+}
+
+// The filesystem path of a `file://` URI, whose reserved and non-ASCII
+// characters are percent-encoded.
+fn uri_path(uri: &str) -> Option<String> {
+    let path = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let hex = path.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (path[i], hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn mnemonic(name: &str) -> Option<&'static (&'static str, bool, &'static str, &'static str)> {
+    MNEMONICS.iter().find(|m| m.0 == name)
+}
+
+// Scans for labels and constants without assembling, so the symbols of a
+// document that does not assemble are still available.
+fn symbols(text: &str) -> Vec<Symbol<'_>> {
+    let mut ret = vec![];
+    for (line, text) in text.lines().enumerate() {
+        let text = split_comment(text).0;
+        let mut toks = tokens(text);
+        if let Some((col, name)) = toks.first().and_then(|(col, t)| Some((*col, t.strip_suffix(':')?))) {
+            if is_label_name(name) {
+                ret.push(Symbol { name, line, start: col - 1, definition: true });
+            }
+            toks.remove(0);
+        }
+        let before = match toks[..] {
+            [(_col, ".proc"), (col, name), ..] => {
+                ret.push(Symbol { name, line, start: col - 1, definition: true });
+                None
+            }
+            [(_col, ".const"), (col, name), ..] => {
+                ret.push(Symbol { name, line, start: col - 1, definition: true });
+                Some((col, name))
+            }
+            [(col, op), ..] if mnemonic(op).is_some_and(|m| m.1) || [".word", ".bytes", ".loop", ".export", ".import"].contains(&op) => Some((col, op)),
+            _ => None
+        };
+        if let Some((col, tok)) = before {
+            let operand = after_token(text, (col, tok));
+            let offset = col - 1 + tok.chars().count();
+            let definition = tok == ".import";
+            ret.extend(names(operand).into_iter().map(|(start, name)| Symbol { name, line, start: offset + operand[..start].chars().count(), definition }));
+        }
+    }
+    ret
+}
+
+// The token under the cursor, with its 0 based start character.
+fn token_at(text: &str, line: usize, character: usize) -> Option<(usize, &str)> {
+    let text = split_comment(text.lines().nth(line)?).0;
+    tokens(text).into_iter()
+        .map(|(col, t)| (col - 1, t))
+        .find(|(start, t)| (*start..=start + t.chars().count()).contains(&character))
+}
+
+pub struct LspServer<W: Write> {
+    out: W,
+    documents: HashMap<String, String>
+}
+
+impl<W: Write> LspServer<W> {
+    pub fn new(out: W) -> LspServer<W> {
+        LspServer { out, documents: HashMap::new() }
+    }
+
+    pub fn serve<R: BufRead>(&mut self, mut input: R) -> std::io::Result<()> {
+        while let Some(message) = read_message(&mut input) {
+            if message.get("method").and_then(|m| m.as_str()) == Some("exit") {
+                break;
+            }
+            self.handle(&message)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> std::io::Result<()> {
+        fields.insert(0, ("jsonrpc", Json::str("2.0")));
+        write_message(&mut self.out, &Json::object(fields))
+    }
+    fn notify(&mut self, method: &str, params: Json) -> std::io::Result<()> {
+        self.send(vec![("method", Json::str(method)), ("params", params)])
+    }
+
+    fn handle(&mut self, message: &Json) -> std::io::Result<()> {
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(|u| u.as_str()).unwrap_or("").to_string();
+        let result = match message.get("method").and_then(|m| m.as_str()).unwrap_or("") {
+            "initialize" => Some(Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    // Full document sync.
+                    ("textDocumentSync", Json::Int(1)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(vec![])),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true))
+                ])),
+                ("serverInfo", Json::object(vec![("name", Json::str("lvm"))]))
+            ])),
+            "shutdown" => Some(Json::Null),
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|d| d.get("text")).and_then(|t| t.as_str()).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri)?;
+                None
+            }
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(|c| c.as_array());
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c.get("text")).and_then(|t| t.as_str()) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                self.publish_diagnostics(&uri)?;
+                None
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.notify("textDocument/publishDiagnostics", Json::object(vec![
+                    ("uri", Json::Str(uri.clone())),
+                    ("diagnostics", Json::Array(vec![]))
+                ]))?;
+                None
+            }
+            "textDocument/hover" => Some(self.hover(&uri, &params)),
+            "textDocument/completion" => Some(self.completion(&uri, &params)),
+            "textDocument/definition" => Some(self.definition(&uri, &params)),
+            "textDocument/references" => Some(self.references(&uri, &params)),
+            "textDocument/documentSymbol" => Some(self.document_symbols(&uri)),
+            _ => None
+        };
+        // Notifications carry no id and get no answer.
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return Ok(())
+        };
+        match result {
+            Some(result) => self.send(vec![("id", id), ("result", result)]),
+            None => self.send(vec![("id", id), ("error", Json::object(vec![
+                ("code", Json::Int(-32601)),
+                ("message", Json::str("Method not found"))
+            ]))])
+        }
+    }
+
+    fn text(&self, uri: &str) -> &str {
+        self.documents.get(uri).map(|t| t.as_str()).unwrap_or("")
+    }
+    // Includes are resolved relative to the document when it is a local file.
+    // As an object, so that imported names are not reported as errors.
+    fn assemble(&self, uri: &str) -> Result<Assembly, AsmError> {
+        assemble_object(self.text(uri), uri_path(uri).as_deref(), &[])
+    }
+    fn cursor(params: &Json) -> (usize, usize) {
+        let position = params.get("position");
+        let get = |key: &str| position.and_then(|p| p.get(key)).and_then(|v| v.as_usize()).unwrap_or(0);
+        (get("line"), get("character"))
+    }
+    // The name of the label under the cursor, whether used or defined there.
+    fn label_at<'a>(text: &'a str, params: &Json) -> Option<&'a str> {
+        let (line, character) = Self::cursor(params);
+        let (_start, tok) = token_at(text, line, character)?;
+        let name = tok.strip_suffix(':').unwrap_or(tok);
+        Some(name).filter(|n| is_label_name(n) && mnemonic(n).is_none())
+    }
+
+    // The assembler stops at the first error, so there is at most one. Errors
+    // in included files are shown on the `.include` line that led to them.
+    fn publish_diagnostics(&mut self, uri: &str) -> std::io::Result<()> {
+        let text = self.text(uri);
+        let diagnostics = match self.assemble(uri) {
+            Ok(_assembly) => vec![],
+            Err(e) => {
+                let (line, message) = match (&e.file, e.included_from.last()) {
+                    (None, _) => (e.line, e.message.clone()),
+                    (Some(_file), Some((None, line))) => (*line, e.to_string()),
+                    // Found after expansion, where the include chain is gone.
+                    (Some(_file), _) => {
+                        let include = text.lines().position(|l| l.trim_start().starts_with(".include"));
+                        (include.map(|i| i + 1).unwrap_or(1), e.to_string())
+                    }
+                };
+                let source = text.lines().nth(line - 1).unwrap_or("");
+                let start = source.chars().take_while(|c| c.is_whitespace()).count();
+                let end = source.trim_end().chars().count().max(start);
+                vec![Json::object(vec![
+                    ("range", range(line - 1, start, end)),
+                    ("severity", Json::Int(SEVERITY_ERROR)),
+                    ("source", Json::str("lvm")),
+                    ("message", Json::Str(message))
+                ])]
+            }
+        };
+        self.notify("textDocument/publishDiagnostics", Json::object(vec![
+            ("uri", Json::str(uri)),
+            ("diagnostics", Json::Array(diagnostics))
+        ]))
+    }
+
+    fn hover(&self, uri: &str, params: &Json) -> Json {
+        let text = self.text(uri);
+        let (line, character) = Self::cursor(params);
+        let tok = match token_at(text, line, character) {
+            Some((_start, tok)) => tok,
+            None => return Json::Null
+        };
+        let value = if let Some((name, operand, effect, doc)) = mnemonic(tok) {
+            let usage = if *operand { format!("{} n", name) } else { name.to_string() };
+            format!("```\n{}    {}\n```\n{}", usage, effect, doc)
+        } else if let Some(name) = Self::label_at(text, params) {
+            let defined = symbols(text).into_iter().find(|s| s.definition && s.name == name);
+            let assembly = self.assemble(uri).ok();
+            let target = assembly.as_ref().and_then(|a| a.labels.iter().find(|(l, _i)| l == name).map(|(_l, i)| *i));
+            let value = assembly.as_ref().and_then(|a| a.consts.iter().find(|(c, _v)| c == name).map(|(_c, v)| *v));
+            let offset = assembly.as_ref().and_then(|a| a.data_labels.iter().find(|(l, _o)| l == name).map(|(_l, o)| *o));
+            let proc = assembly.as_ref().and_then(|a| a.procs.iter().find(|p| p.name == name));
+            let imported = assembly.as_ref().is_some_and(|a| a.imports.iter().any(|i| i == name));
+            match (defined, target, offset, value) {
+                (Some(s), None, None, None) if imported => format!("imported label `{}`, line {}", name, s.line + 1),
+                (Some(s), Some(i), _, _) => match proc {
+                    Some(p) => format!("procedure `{}` {}, line {}, instruction {}", name, p.effect, s.line + 1, i),
+                    None => format!("label `{}`, line {}, instruction {}", name, s.line + 1, i)
+                },
+                (Some(s), None, Some(o), _) => format!("data label `{}`, line {}, offset {}", name, s.line + 1, o),
+                (Some(s), None, None, Some(v)) => format!("constant `{}` = {}, line {}", name, v, s.line + 1),
+                (Some(s), None, None, None) => format!("`{}`, line {}", name, s.line + 1),
+                (None, _, _, _) => format!("undefined name `{}`", name)
+            }
+        } else {
+            return Json::Null;
+        };
+        Json::object(vec![("contents", Json::object(vec![("kind", Json::str("markdown")), ("value", Json::Str(value))]))])
+    }
+
+    // Mnemonics where an instruction starts, labels in operand position.
+    fn completion(&self, uri: &str, params: &Json) -> Json {
+        let text = self.text(uri);
+        let (line, character) = Self::cursor(params);
+        let prefix: String = text.lines().nth(line).unwrap_or("").chars().take(character).collect();
+        if split_comment(&prefix).1.is_some() {
+            return Json::Array(vec![]);
+        }
+        let mut before = tokens(&prefix);
+        if before.first().is_some_and(|(_col, t)| t.ends_with(':')) {
+            before.remove(0);
+        }
+        let typing = !prefix.ends_with(char::is_whitespace) && !before.is_empty();
+        let items = match before.len() - typing as usize {
+            0 => MNEMONICS.iter().map(|(name, _operand, effect, doc)| Json::object(vec![
+                ("label", Json::str(name)),
+                ("kind", Json::Int(COMPLETION_KEYWORD)),
+                ("detail", Json::str(effect)),
+                ("documentation", Json::str(doc))
+            ])).collect(),
+            1 if mnemonic(before[0].1).is_some_and(|m| m.1) => symbols(text).into_iter()
+                .filter(|s| s.definition)
+                .map(|s| Json::object(vec![
+                    ("label", Json::str(s.name)),
+                    ("kind", Json::Int(COMPLETION_REFERENCE)),
+                    ("detail", Json::Str(format!("line {}", s.line + 1)))
+                ]))
+                .collect(),
+            _ => vec![]
+        };
+        Json::Array(items)
+    }
+
+    fn definition(&self, uri: &str, params: &Json) -> Json {
+        let text = self.text(uri);
+        let name = match Self::label_at(text, params) {
+            Some(name) => name,
+            None => return Json::Null
+        };
+        match symbols(text).into_iter().find(|s| s.definition && s.name == name) {
+            Some(s) => Json::object(vec![("uri", Json::str(uri)), ("range", s.range())]),
+            None => Json::Null
+        }
+    }
+
+    fn references(&self, uri: &str, params: &Json) -> Json {
+        let text = self.text(uri);
+        let declaration = params.get("context").and_then(|c| c.get("includeDeclaration")).and_then(|d| d.as_bool()).unwrap_or(true);
+        let name = match Self::label_at(text, params) {
+            Some(name) => name,
+            None => return Json::Array(vec![])
+        };
+        Json::Array(symbols(text).into_iter()
+            .filter(|s| s.name == name && (declaration || !s.definition))
+            .map(|s| Json::object(vec![("uri", Json::str(uri)), ("range", s.range())]))
+            .collect())
+    }
+
+    fn document_symbols(&self, uri: &str) -> Json {
+        let text = self.text(uri);
+        Json::Array(symbols(text).into_iter()
+            .filter(|s| s.definition)
+            .map(|s| Json::object(vec![
+                ("name", Json::str(s.name)),
+                ("kind", Json::Int(SYMBOL_FUNCTION)),
+                ("range", s.range()),
+                ("selectionRange", s.range())
+            ]))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///nowhere/main.vm";
+    const SOURCE: &str = ".const N 3\nstart: push N ; count\nloop:\n    push 1\n    sub\n    jnz loop\n    jmp start\n";
+
+    fn message(id: Option<i128>, method: &str, params: Json) -> Json {
+        let mut fields = vec![("jsonrpc", Json::str("2.0")), ("method", Json::str(method)), ("params", params)];
+        if let Some(id) = id {
+            fields.push(("id", Json::Int(id)));
+        }
+        Json::object(fields)
+    }
+
+    fn open(uri: &str, text: &str) -> Json {
+        message(None, "textDocument/didOpen", Json::object(vec![("textDocument", Json::object(vec![("uri", Json::str(uri)), ("text", Json::str(text))]))]))
+    }
+
+    fn at(method: &str, line: usize, character: usize) -> Json {
+        message(Some(1), method, Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::str(URI))])),
+            ("position", position(line, character))
+        ]))
+    }
+
+    // Serves `messages` and returns what was sent back.
+    fn serve(messages: &[Json]) -> Vec<Json> {
+        let mut input = vec![];
+        for m in messages {
+            write_message(&mut input, m).unwrap();
+        }
+        let mut server = LspServer::new(vec![]);
+        server.serve(Cursor::new(input)).unwrap();
+        let mut out = Cursor::new(server.out);
+        std::iter::from_fn(|| read_message(&mut out)).collect()
+    }
+
+    // The result of the last request, after opening SOURCE.
+    fn result(request: Json) -> Json {
+        let replies = serve(&[open(URI, SOURCE), request]);
+        replies.last().and_then(|r| r.get("result")).cloned().unwrap()
+    }
+
+    fn hover(line: usize, character: usize) -> String {
+        let result = result(at("textDocument/hover", line, character));
+        result.get("contents").and_then(|c| c.get("value")).and_then(|v| v.as_str()).unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn file_uris_are_percent_decoded() {
+        assert_eq!(uri_path("file:///tmp/a%20b/%C3%A9.vm").as_deref(), Some("/tmp/a b/é.vm"));
+        assert_eq!(uri_path("file:///tmp/100%.vm").as_deref(), Some("/tmp/100%.vm"));
+        assert_eq!(uri_path("file:///tmp/%zz.vm").as_deref(), Some("/tmp/%zz.vm"));
+        assert_eq!(uri_path("untitled:Untitled-1"), None);
+    }
+
+    #[test]
+    fn diagnostics_follow_the_document() {
+        let change = message(None, "textDocument/didChange", Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::str(URI))])),
+            ("contentChanges", Json::Array(vec![Json::object(vec![("text", Json::str("push 1\n  jmp nowhere  \n"))])]))
+        ]));
+        let replies = serve(&[open(URI, SOURCE), change, message(None, "exit", Json::Null), open(URI, "bogus\n")]);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].to_string(), format!("{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":\"{}\",\"diagnostics\":[]}}}}", URI));
+        let diagnostics = replies[1].get("params").and_then(|p| p.get("diagnostics")).unwrap();
+        assert_eq!(diagnostics.to_string(), "[{\"range\":{\"start\":{\"line\":1,\"character\":2},\"end\":{\"line\":1,\"character\":13}},\"severity\":1,\"source\":\"lvm\",\"message\":\"Undefined name `nowhere`\"}]");
+    }
+
+    #[test]
+    fn includes_resolve_from_percent_encoded_uris() {
+        let dir = std::env::temp_dir().join(format!("lvm lsp é {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.vm"), "push 1\n").unwrap();
+        std::fs::write(dir.join("bad.vm"), "push 1\nbogus\n").unwrap();
+        let encoded: String = dir.join("main.vm").to_str().unwrap().bytes().map(|b| match b {
+            b'/' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{:02X}", b)
+        }).collect();
+        let uri = format!("file://{}", encoded);
+        assert!(uri.contains("%20%C3%A9%20"));
+        let replies = serve(&[open(&uri, ".include \"good.vm\"\nhalt\n"), open(&uri, "push 0\n.include \"bad.vm\"\n")]);
+        let diagnostics: Vec<String> = replies.iter().map(|r| r.get("params").and_then(|p| p.get("diagnostics")).unwrap().to_string()).collect();
+        assert_eq!(diagnostics[0], "[]");
+        assert!(diagnostics[1].starts_with("[{\"range\":{\"start\":{\"line\":1,\"character\":0},\"end\":{\"line\":1,\"character\":17}},"), "{}", diagnostics[1]);
+        assert!(diagnostics[1].contains("bad.vm:2"), "{}", diagnostics[1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hover_describes_mnemonics_and_names() {
+        assert_eq!(hover(3, 5), "```\npush n    ( -- n )\n```\nPushes the operand.");
+        assert_eq!(hover(4, 6), "```\nsub    ( a b -- a-b )\n```\nSubtracts the top value from the one below it.");
+        assert_eq!(hover(5, 10), "label `loop`, line 3, instruction 1");
+        assert_eq!(hover(1, 1), "label `start`, line 2, instruction 0");
+        assert_eq!(hover(1, 12), "constant `N` = 3, line 1");
+        assert_eq!(result(at("textDocument/hover", 1, 16)), Json::Null);
+        assert_eq!(result(at("textDocument/hover", 2, 20)), Json::Null);
+    }
+
+    #[test]
+    fn definitions_and_references() {
+        assert_eq!(result(at("textDocument/definition", 5, 9)).to_string(), format!("{{\"uri\":\"{}\",\"range\":{}}}", URI, range(2, 0, 4)));
+        assert_eq!(result(at("textDocument/definition", 6, 10)).get("range"), Some(&range(1, 0, 5)));
+        assert_eq!(result(at("textDocument/definition", 3, 5)), Json::Null);
+        let references = result(at("textDocument/references", 2, 1));
+        let ranges: Vec<Json> = references.as_array().unwrap().iter().map(|r| r.get("range").cloned().unwrap()).collect();
+        assert_eq!(ranges, [range(2, 0, 4), range(5, 8, 12)]);
+        let symbols = result(message(Some(1), "textDocument/documentSymbol", Json::object(vec![("textDocument", Json::object(vec![("uri", Json::str(URI))]))])));
+        let names: Vec<&str> = symbols.as_array().unwrap().iter().filter_map(|s| s.get("name").and_then(|n| n.as_str())).collect();
+        assert_eq!(names, ["N", "start", "loop"]);
+    }
+
+    #[test]
+    fn unknown_requests_get_an_error() {
+        let replies = serve(&[message(Some(7), "workspace/frobnicate", Json::Null), message(None, "initialized", Json::Null)]);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].to_string(), "{\"jsonrpc\":\"2.0\",\"id\":7,\"error\":{\"code\":-32601,\"message\":\"Method not found\"}}");
+    }
+}
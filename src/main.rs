@@ -1,31 +1,395 @@
-mod vm;
-#[path ="./utils/list.rs"]
-mod list;
-#[path ="./utils/string.rs"]
-mod string;
-#[path ="./utils/io.rs"]
-mod io;
+use std::cell::RefCell;
+use std::process::exit;
+use std::rc::Rc;
+use lvm::{vm::{VM, RunOutcome, Instruction, ExitCode}, asm::{assemble_object, assemble_with, expand_with, Assembly}, obj::{self, Object}, profile::Profiler, coverage::Coverage, listing::listing, replay::{self, Recording}, observer::{CountingObserver, PrintingObserver}, trace::JsonTracer, debugger::Debugger, dap::DapServer, lsp::LspServer, fmt, verify, repl::Repl, list::AsList, cout};
 
-use crate::{vm::VM, list::{AsList, Tail, Head}, string::StringView, io::cout};
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("\tlvm compile <source.vm> <program.ekvm> [-g] [--listing <file.lst>] [-I <dir>]...");
+    eprintln!("\tlvm compile -c <source.vm> <object.lvo> [-g] [--listing <file.lst>] [-I <dir>]...");
+    eprintln!("\tlvm link <object.lvo>... -o <program.ekvm>");
+    eprintln!("\tlvm expand <source.vm> [-I <dir>]...");
+    eprintln!("\tlvm fmt [--check] <source.vm>... [-I <dir>]...");
+    eprintln!("\tlvm run <program.ekvm> [--checkpoint <snapshot> <every>] [--verbose] [--trace] [--stats]");
+    eprintln!("\tlvm resume <snapshot> [--checkpoint <snapshot> <every>] [--verbose] [--trace] [--stats]");
+    eprintln!("\tlvm debug <program.ekvm> [--record <n>]");
+    eprintln!("\tlvm verify <program.vm|program.ekvm> [-I <dir>]...");
+    eprintln!("\tlvm dap");
+    eprintln!("\tlvm lsp");
+    eprintln!("\tlvm repl");
+    eprintln!("\tlvm profile <program.vm|program.ekvm> [--folded <file>]");
+    eprintln!("\tlvm coverage <program.vm> [--lcov <file>]");
+    eprintln!("\tlvm record <program.ekvm> <log>");
+    eprintln!("\tlvm replay <program.ekvm> <log>");
+    eprintln!("Runtime errors are reported on stderr; pass --error-format json for a machine-readable report.");
+    eprintln!("replay re-runs the program and compares output, outcome and stack with the log; no instruction reads input, so none is recorded.");
+    eprintln!("Trace options for run and resume:");
+    eprintln!("\t--trace-json <file> [--trace-range <from>..<to>] [--trace-top <n>] [--trace-max-bytes <n>]");
+    exit(1);
+}
 
-#[allow(unused_must_use)]
+fn finish(outcome: RunOutcome, json_errors: bool) -> ! {
+    if let RunOutcome::Error(error) = &outcome {
+        if json_errors {
+            eprintln!("{}", error.to_json());
+        } else {
+            eprint!("{}", error.report());
+        }
+    }
+    exit(outcome.exit_code());
+}
+
+// Whether two assemblies differ only in source positions.
+fn same_program(a: &Assembly, b: &Assembly) -> bool {
+    a.program == b.program && a.data == b.data && a.labels == b.labels && a.data_labels == b.data_labels && a.consts == b.consts
+}
+
+// .vm sources are assembled in memory so that tools can refer back to
+// source lines.
+fn load_any(path: &str, include_dirs: &[String]) -> std::io::Result<(Vec<Instruction>, Vec<u8>, Option<Assembly>)> {
+    if path.ends_with(".vm") {
+        match assemble_with(&std::fs::read_to_string(path)?, Some(path), include_dirs) {
+            Ok(assembly) => Ok((assembly.program.clone(), assembly.data.clone(), Some(assembly))),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                exit(ExitCode::FEXT as i32);
+            }
+        }
+    } else {
+        let mut vm = VM::init();
+        vm.load_from_file(path)?;
+        Ok((vm.program().to_vec(), vm.data().to_vec(), None))
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, names: &[&str]) -> bool {
+    let found = args.iter().any(|a| names.contains(&a.as_str()));
+    args.retain(|a| !names.contains(&a.as_str()));
+    found
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    if i + 1 >= args.len() {
+        usage();
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Some(value)
+}
+
+fn parse_or_usage<T: std::str::FromStr>(s: &str) -> T {
+    s.parse::<T>().unwrap_or_else(|_e| usage())
+}
 
+fn json_tracer(args: &mut Vec<String>) -> std::io::Result<Option<JsonTracer>> {
+    let range = take_option(args, "--trace-range");
+    let top = take_option(args, "--trace-top");
+    let max_bytes = take_option(args, "--trace-max-bytes");
+    let path = match take_option(args, "--trace-json") {
+        Some(path) => path,
+        None => return Ok(None)
+    };
+    let mut tracer = JsonTracer::create(&path)?;
+    if let Some(range) = range {
+        match range.split_once("..") {
+            Some((from, to)) => tracer = tracer.pc_range(parse_or_usage(from)..parse_or_usage(to)),
+            None => usage()
+        }
+    }
+    if let Some(top) = top {
+        tracer = tracer.top_n(parse_or_usage(&top));
+    }
+    if let Some(max_bytes) = max_bytes {
+        tracer = tracer.max_bytes(parse_or_usage(&max_bytes));
+    }
+    Ok(Some(tracer))
+}
+
+fn checkpoint_arg(args: &[String]) -> Option<(&str, usize)> {
+    match args {
+        [] => None,
+        [flag, path, every] if flag == "--checkpoint" => match every.parse::<usize>() {
+            Ok(every) if every > 0 => Some((path.as_str(), every)),
+            _ => usage()
+        },
+        _ => usage()
+    }
+}
+
+#[allow(unused_must_use)]
 fn main() -> std::io::Result<()> {
-    let list = vec![1,2,3,4,5,6].as_list();
+    let mut args: Vec<String> = std::env::args().collect();
+    let verbose = take_flag(&mut args, &["--verbose", "-v"]);
+    let trace = take_flag(&mut args, &["--trace"]);
+    let stats = take_flag(&mut args, &["--stats"]);
+    let folded = take_option(&mut args, "--folded");
+    let lcov = take_option(&mut args, "--lcov");
+    let record = take_option(&mut args, "--record");
+    let debug_info = take_flag(&mut args, &["-g", "--debug-info"]);
+    let check = take_flag(&mut args, &["--check"]);
+    let object = take_flag(&mut args, &["-c"]);
+    let output = take_option(&mut args, "-o");
+    let lst = take_option(&mut args, "--listing");
+    let mut include_dirs = vec![];
+    while let Some(dir) = take_option(&mut args, "-I") {
+        include_dirs.push(dir);
+    }
+    let json_errors = match take_option(&mut args, "--error-format").as_deref() {
+        None | Some("human") => false,
+        Some("json") => true,
+        Some(_) => usage()
+    };
+    let mut json_tracer = json_tracer(&mut args)?;
+    let counter = Rc::new(RefCell::new(CountingObserver::new()));
+    let mut attach = |vm: &mut VM| {
+        vm.set_verbose(verbose);
+        if trace {
+            vm.add_observer(Box::new(PrintingObserver));
+        }
+        if stats {
+            vm.add_observer(Box::new(counter.clone()));
+        }
+        if let Some(tracer) = json_tracer.take() {
+            vm.add_observer(Box::new(tracer));
+        }
+    };
     let mut vm = VM::init();
-    cout![]
-    % String::from("hello").as_list()
-    | "\n";
-    
-    /*vm.load_program(vec![
-        Instruction::PUSH(1),
-        Instruction::DUMP
-    ]);
-
-    vm.write_to_file("foo.ekvm")?;*/
-    //vm.load_from_file("foo.ekvm")?;
-    VM::compile_source("foo.vm", "foo.ekvm")?;
-    vm.load_from_file("foo.ekvm")?;
-    vm.run_program();
-    Ok(())
-}
\ No newline at end of file
+    let outcome = match args.get(1).map(|a| a.as_str()) {
+        None => {
+            (cout![]
+            % String::from("hello").as_list())
+            | "\n";
+
+            /*vm.load_program(vec![
+                Instruction::PUSH(1),
+                Instruction::DUMP
+            ]);
+
+            vm.write_to_file("foo.ekvm")?;*/
+            //vm.load_from_file("foo.ekvm")?;
+            VM::compile_source("foo.vm", "foo.ekvm")?;
+            vm.load_from_file("foo.ekvm")?;
+            attach(&mut vm);
+            vm.run_program()
+        }
+        Some("compile") if args.len() == 4 && object => {
+            let source = std::fs::read_to_string(&args[2])?;
+            let assembly = match assemble_object(&source, Some(&args[2]), &include_dirs) {
+                Ok(assembly) => assembly,
+                Err(e) => {
+                    eprintln!("{}: {}", args[2], e);
+                    exit(ExitCode::FEXT as i32);
+                }
+            };
+            if let Some(lst) = &lst {
+                std::fs::write(lst, listing(&assembly, &source, &args[2]))?;
+            }
+            Object::from_assembly(assembly, debug_info.then_some(args[2].as_str())).write_to_file(&args[3])?;
+            return Ok(());
+        }
+        Some("link") if args.len() >= 3 && output.is_some() => {
+            let mut objects = vec![];
+            for path in &args[2..] {
+                match Object::load_from_file(path) {
+                    Ok(object) => objects.push((path.clone(), object)),
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        exit(ExitCode::FEXT as i32);
+                    }
+                }
+            }
+            match obj::link(&objects) {
+                Ok(linked) => linked.write_to_file(output.as_deref().unwrap_or_default())?,
+                Err(errors) => {
+                    errors.iter().for_each(|e| eprintln!("{}", e));
+                    exit(ExitCode::FEXT as i32);
+                }
+            }
+            return Ok(());
+        }
+        Some("compile") if args.len() == 4 => {
+            // The listing and the bytecode come from the same assembly.
+            let source = std::fs::read_to_string(&args[2])?;
+            let assembly = match assemble_with(&source, Some(&args[2]), &include_dirs) {
+                Ok(assembly) => assembly,
+                Err(e) => {
+                    eprintln!("{}: {}", args[2], e);
+                    exit(ExitCode::FEXT as i32);
+                }
+            };
+            if let Some(lst) = &lst {
+                std::fs::write(lst, listing(&assembly, &source, &args[2]))?;
+            }
+            VM::write_assembly(assembly, &args[2], &args[3], debug_info)?;
+            return Ok(());
+        }
+        Some("fmt") if args.len() >= 3 => {
+            let mut unformatted = false;
+            for path in &args[2..] {
+                let source = std::fs::read_to_string(path)?;
+                let formatted = fmt::format(&source);
+                if formatted == source {
+                    continue;
+                }
+                // Formatting must not change what a file assembles to.
+                let before = assemble_with(&source, Some(path), &include_dirs);
+                let after = assemble_with(&formatted, Some(path), &include_dirs);
+                match (before, after) {
+                    (Ok(before), Ok(after)) if same_program(&before, &after) => {}
+                    // Nothing to compare against when it does not assemble yet.
+                    (Err(_e), _) => {}
+                    _ => {
+                        eprintln!("{}: formatting would change the assembled program, leaving it as is.", path);
+                        exit(ExitCode::FEXT as i32);
+                    }
+                }
+                if check {
+                    println!("{}", path);
+                    unformatted = true;
+                } else {
+                    std::fs::write(path, formatted)?;
+                }
+            }
+            exit(if unformatted { 1 } else { 0 });
+        }
+        Some("verify") if args.len() == 3 => {
+            // Assembling a .vm source already checks its procedures.
+            let (program, _data, assembly) = load_any(&args[2], &include_dirs)?;
+            let procs = match &assembly {
+                Some(assembly) => assembly.procs.clone(),
+                None => {
+                    vm.load_from_file(&args[2])?;
+                    vm.procs().to_vec()
+                }
+            };
+            let mut failed = false;
+            for proc in &procs {
+                if let Err((pc, message)) = verify::check_proc(&program, proc) {
+                    match vm.debug_info().and_then(|d| d.locations.get(pc)) {
+                        Some(loc) => eprintln!("{}: {}", loc, message),
+                        None => eprintln!("{}: instruction {}: {}", args[2], pc, message)
+                    }
+                    failed = true;
+                }
+            }
+            if failed {
+                exit(ExitCode::FEXT as i32);
+            }
+            println!("{}: every procedure matches its stack effect ({} checked).", args[2], procs.len());
+            return Ok(());
+        }
+        Some("expand") if args.len() == 3 => {
+            match expand_with(&std::fs::read_to_string(&args[2])?, Some(&args[2]), &include_dirs) {
+                Ok(lines) => lines.iter().for_each(|l| println!("{}", l.text)),
+                Err(e) => {
+                    eprintln!("{}: {}", args[2], e);
+                    exit(ExitCode::FEXT as i32);
+                }
+            }
+            return Ok(());
+        }
+        Some("run") if args.len() >= 3 => {
+            vm.load_from_file(&args[2])?;
+            attach(&mut vm);
+            vm.run_checkpointed(checkpoint_arg(&args[3..]))
+        }
+        Some("resume") if args.len() >= 3 => {
+            vm = VM::load_snapshot(&args[2])?;
+            attach(&mut vm);
+            vm.run_checkpointed(checkpoint_arg(&args[3..]))
+        }
+        Some("debug") if args.len() == 3 => {
+            vm.load_from_file(&args[2])?;
+            let mut debugger = Debugger::new(vm.program().to_vec())
+                .with_data(vm.data().to_vec())
+                .with_debug_info(vm.debug_info().cloned());
+            if let Some(cap) = record {
+                debugger.record(parse_or_usage(&cap));
+            }
+            debugger.repl(std::io::stdin().lock());
+            return Ok(());
+        }
+        Some("dap") if args.len() == 2 => {
+            return DapServer::new(std::io::stdout()).serve(std::io::stdin());
+        }
+        Some("lsp") if args.len() == 2 => {
+            return LspServer::new(std::io::stdout()).serve(std::io::stdin().lock());
+        }
+        Some("profile") if args.len() == 3 => {
+            let (program, data, assembly) = load_any(&args[2], &include_dirs)?;
+            let mut profiler = Profiler::new(&program);
+            if let Some(assembly) = assembly {
+                profiler = profiler.with_source(&args[2], &assembly);
+            }
+            let profiler = Rc::new(RefCell::new(profiler));
+            vm.load_program(program);
+            vm.set_data(data);
+            attach(&mut vm);
+            vm.add_observer(Box::new(profiler.clone()));
+            let outcome = vm.run_program();
+            drop(vm.clear_observers());
+            let mut profiler = profiler.borrow_mut();
+            profiler.finish();
+            eprint!("{}", profiler.report());
+            if let Some(path) = folded {
+                std::fs::write(path, profiler.folded())?;
+            }
+            outcome
+        }
+        Some("coverage") if args.len() == 3 => {
+            let assembly = match load_any(&args[2], &include_dirs)? {
+                (_program, _data, Some(assembly)) => assembly,
+                (_program, _data, None) => {
+                    eprintln!("Coverage is reported against the source, pass a .vm file.");
+                    exit(ExitCode::FEXT as i32);
+                }
+            };
+            let coverage = Rc::new(RefCell::new(Coverage::new(&assembly.program)));
+            vm.load_program(assembly.program.clone());
+            vm.set_data(assembly.data.clone());
+            attach(&mut vm);
+            vm.add_observer(Box::new(coverage.clone()));
+            let outcome = vm.run_program();
+            drop(vm.clear_observers());
+            let coverage = coverage.borrow();
+            eprint!("{}", coverage.summary());
+            let source = std::fs::canonicalize(&args[2])?;
+            std::fs::write(lcov.unwrap_or("lcov.info".to_string()), coverage.lcov(&source.to_string_lossy(), &assembly))?;
+            outcome
+        }
+        Some("record") if args.len() == 4 => {
+            vm.load_from_file(&args[2])?;
+            attach(&mut vm);
+            let (outcome, recording) = replay::record(&mut vm);
+            recording.save(&args[3])?;
+            outcome
+        }
+        Some("replay") if args.len() == 4 => {
+            let expected = Recording::load(&args[3])?;
+            vm.load_from_file(&args[2])?;
+            attach(&mut vm);
+            let (outcome, mismatches) = replay::replay(&mut vm, &expected);
+            if !mismatches.is_empty() {
+                for m in mismatches {
+                    eprintln!("Replay mismatch: {}", m);
+                }
+                exit(ExitCode::FEXT as i32);
+            }
+            eprintln!("Replay matches the recording.");
+            outcome
+        }
+        Some("repl") if args.len() == 2 => {
+            Repl::new().run(std::io::stdin().lock());
+            return Ok(());
+        }
+        _ => usage()
+    };
+    // `finish` exits without running destructors, so buffered observers
+    // such as the JSON tracer have to be dropped here.
+    drop(vm.clear_observers());
+    if stats {
+        eprint!("{}", counter.borrow().report());
+    }
+    finish(outcome, json_errors)
+}
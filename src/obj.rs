@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::asm::Assembly;
+use crate::vm::{split_section, DebugInfo, Instruction, Proc, Word, VM};
+
+const LINK_SECTION: &str = ".link";
+
+// What a relocation adds: the address the object's code or data ends up at,
+// or the value of a symbol exported by another object.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Code,
+    Data,
+    Symbol(String)
+}
+
+// Where a relocation applies: the operand of an instruction, or an 8 byte
+// word of the data segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Place {
+    Code(usize),
+    Data(usize)
+}
+
+// Adds `factor` times the value of `target` at `place` when linking. The
+// factor is usually 1, or -1 for an address subtracted from a number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloc {
+    pub place: Place,
+    pub target: Target,
+    pub factor: i64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Section {
+    Code,
+    Data
+}
+
+// A separately assembled source. Offsets, local jump targets and exported
+// symbols are relative to the start of the object's own code or data, as if
+// it were the whole program; the linker moves them into place. Stored like a
+// bytecode file followed by a `.link` section with one entry per line:
+//     export <name> code|data <offset>
+//     import <name>
+//     reloc code|data <index or offset> code|data|symbol [<name>] <factor>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub program: Vec<Instruction>,
+    pub data: Vec<u8>,
+    pub exports: Vec<(String, Section, usize)>,
+    pub imports: Vec<String>,
+    pub relocs: Vec<Reloc>,
+    pub procs: Vec<Proc>,
+    pub debug: Option<DebugInfo>
+}
+
+impl fmt::Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Place::Code(i) => write!(f, "code {}", i),
+            Place::Data(o) => write!(f, "data {}", o)
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Code => write!(f, "code"),
+            Target::Data => write!(f, "data"),
+            Target::Symbol(name) => write!(f, "symbol {}", name)
+        }
+    }
+}
+
+impl Object {
+    // `file` names the source in the debug entries, which are only kept if
+    // it is given.
+    pub fn from_assembly(assembly: Assembly, file: Option<&str>) -> Object {
+        let exports = assembly.exports.iter()
+            .filter_map(|name| {
+                let code = assembly.labels.iter().find(|(l, _i)| l == name).map(|(_l, i)| (name.clone(), Section::Code, *i));
+                code.or_else(|| assembly.data_labels.iter().find(|(l, _o)| l == name).map(|(_l, o)| (name.clone(), Section::Data, *o)))
+            })
+            .collect();
+        Object {
+            debug: file.map(|f| assembly.debug_info(f)),
+            program: assembly.program,
+            data: assembly.data,
+            exports,
+            imports: assembly.imports,
+            relocs: assembly.relocs,
+            procs: assembly.procs
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut vm = VM::init();
+        vm.load_program(self.program.clone());
+        vm.set_data(self.data.clone());
+        vm.set_procs(self.procs.clone());
+        vm.set_debug_info(self.debug.clone());
+        let mut ret = format!("{}\n{}\n", vm.encode(), LINK_SECTION);
+        for (name, section, offset) in &self.exports {
+            ret += format!("export {} {} {}\n", name, if *section == Section::Code { "code" } else { "data" }, offset).as_str();
+        }
+        for name in &self.imports {
+            ret += format!("import {}\n", name).as_str();
+        }
+        for r in &self.relocs {
+            ret += format!("reloc {} {} {}\n", r.place, r.target, r.factor).as_str();
+        }
+        ret
+    }
+
+    pub fn decode(text: &str) -> std::io::Result<Object> {
+        let (rest, section) = match split_section(text, LINK_SECTION) {
+            (rest, Some(section)) => (rest, section),
+            (_rest, None) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not an object file, there is no `.link` section"))
+        };
+        let mut vm = VM::init();
+        vm.decode(rest)?;
+        let mut ret = Object {
+            program: vm.program().to_vec(),
+            data: vm.data().to_vec(),
+            exports: vec![],
+            imports: vec![],
+            relocs: vec![],
+            procs: vm.procs().to_vec(),
+            debug: vm.debug_info().cloned()
+        };
+        for line in section.lines().filter(|l| !l.trim().is_empty()) {
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid link entry `{}`", line));
+            let number = |s: &str| s.parse::<usize>().map_err(|_e| invalid());
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                ["export", name, "code", offset] => ret.exports.push((name.to_string(), Section::Code, number(offset)?)),
+                ["export", name, "data", offset] => ret.exports.push((name.to_string(), Section::Data, number(offset)?)),
+                ["import", name] => ret.imports.push(name.to_string()),
+                ["reloc", place, at, ref target @ .., factor] => {
+                    let place = match place {
+                        "code" => Place::Code(number(at)?),
+                        "data" => Place::Data(number(at)?),
+                        _ => return Err(invalid())
+                    };
+                    let target = match target {
+                        ["code"] => Target::Code,
+                        ["data"] => Target::Data,
+                        ["symbol", name] => Target::Symbol(name.to_string()),
+                        _ => return Err(invalid())
+                    };
+                    let factor = factor.parse::<i64>().map_err(|_e| invalid())?;
+                    ret.relocs.push(Reloc { place, target, factor });
+                }
+                _ => return Err(invalid())
+            }
+        }
+        // Relocations must stay inside the object.
+        for r in &ret.relocs {
+            let inside = match r.place {
+                Place::Code(i) => ret.program.get(i).is_some_and(|inst| inst.operand().is_some()),
+                Place::Data(o) => o.checked_add(8).is_some_and(|end| end <= ret.data.len())
+            };
+            if !inside {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Relocation at {} is outside the object", r.place)));
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn write_to_file(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.encode())
+    }
+
+    pub fn load_from_file(path: &str) -> std::io::Result<Object> {
+        Object::decode(&std::fs::read_to_string(path)?)
+    }
+}
+
+// Combines objects, in order, into one program that starts with the code of
+// the first. Every object's code and data follow those of the objects before
+// it; relocations then add where each object ended up, or the value of the
+// symbol they refer to. Returns every unresolved or duplicate symbol as an
+// error. The debug section is kept only if all objects have one.
+pub fn link(objects: &[(String, Object)]) -> Result<VM, Vec<String>> {
+    let mut errors = vec![];
+    let mut program = Vec::<Instruction>::new();
+    let mut data = Vec::<u8>::new();
+    let mut procs = vec![];
+    let mut debug = Some(DebugInfo::default());
+    let mut bases = vec![];
+    let mut symbols: HashMap<&str, (&str, Word)> = HashMap::new();
+    for (path, object) in objects {
+        let (code_base, data_base) = (program.len(), data.len());
+        bases.push((code_base, data_base));
+        program.extend(&object.program);
+        data.extend(&object.data);
+        for (name, section, offset) in &object.exports {
+            let value = (offset + if *section == Section::Code { code_base } else { data_base }) as Word;
+            match symbols.get(name.as_str()) {
+                Some((other, _value)) => errors.push(format!("Duplicate symbol `{}` exported by {} and {}", name, other, path)),
+                None => {
+                    symbols.insert(name, (path, value));
+                }
+            }
+        }
+        procs.extend(object.procs.iter().map(|p| Proc { start: p.start + code_base, end: p.end + code_base, ..p.clone() }));
+        debug = match (debug, &object.debug) {
+            (Some(mut all), Some(d)) => {
+                all.locations.extend(d.locations.iter().cloned());
+                all.labels.extend(d.labels.iter().map(|(name, i)| (name.clone(), i + code_base)));
+                Some(all)
+            }
+            _ => None
+        };
+    }
+    for ((path, object), (code_base, data_base)) in objects.iter().zip(bases) {
+        for name in &object.imports {
+            if !symbols.contains_key(name.as_str()) {
+                errors.push(format!("Undefined symbol `{}` imported by {}", name, path));
+            }
+        }
+        for r in &object.relocs {
+            let value = match &r.target {
+                Target::Code => code_base as Word,
+                Target::Data => data_base as Word,
+                Target::Symbol(name) => match symbols.get(name.as_str()) {
+                    Some((_path, value)) => *value,
+                    // Reported with the imports.
+                    None => continue
+                }
+            };
+            let delta = value.wrapping_mul(r.factor as Word);
+            match r.place {
+                Place::Code(i) => {
+                    let inst = program[code_base + i];
+                    program[code_base + i] = inst.with_operand(inst.operand().unwrap_or(0).wrapping_add(delta));
+                }
+                Place::Data(o) => {
+                    let at = data_base + o;
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(&data[at..at + 8]);
+                    data[at..at + 8].copy_from_slice(&Word::from_le_bytes(word).wrapping_add(delta).to_le_bytes());
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut vm = VM::init();
+    vm.load_program(program);
+    vm.set_data(data);
+    vm.set_procs(procs);
+    vm.set_debug_info(debug);
+    Ok(vm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+    use crate::vm::RunOutcome;
+
+    fn object(source: &str) -> Object {
+        Object::from_assembly(assemble_object(source, None, &[]).unwrap(), None)
+    }
+
+    fn objects(sources: &[(&str, &str)]) -> Vec<(String, Object)> {
+        sources.iter().map(|(path, source)| (path.to_string(), object(source))).collect()
+    }
+
+    const MAIN: &str = ".import square\n.export back\n.data\nbytes: .bytes 1, 2, 3\n.text\n    push 7\n    jmp square\nback:\n    halt\n";
+    const SQUARE: &str = ".import back\n.export square\n.data\ntable: .word 5, table\n.text\nsquare:\n    dup 0\n    mul\n    jmp back\nloop:\n    jmp loop\n    push table + 8\n";
+
+    #[test]
+    fn links_objects_in_order() {
+        let mut vm = link(&objects(&[("main.vm", MAIN), ("square.vm", SQUARE)])).unwrap();
+        assert_eq!(vm.program(), &[
+            Instruction::PUSH(7), Instruction::JMP(3), Instruction::HALT,
+            Instruction::DUP(0), Instruction::MUL, Instruction::JMP(2), Instruction::JMP(6), Instruction::PUSH(11)
+        ]);
+        // `table` moved behind the 3 bytes of main.vm, and so did the word
+        // holding its address.
+        let mut data = vec![1, 2, 3];
+        data.extend(5u64.to_le_bytes());
+        data.extend(3u64.to_le_bytes());
+        assert_eq!(vm.data(), data.as_slice());
+        vm.set_output(Box::new(std::io::sink()));
+        assert_eq!(vm.run_program(), RunOutcome::Halted(49));
+    }
+
+    #[test]
+    fn reports_every_unresolved_and_duplicate_symbol() {
+        let errors = link(&objects(&[("main.vm", MAIN)])).unwrap_err();
+        assert_eq!(errors, vec!["Undefined symbol `square` imported by main.vm"]);
+        let other = ".export back, square\nback:\nsquare:\n    halt\n";
+        let errors = link(&objects(&[("main.vm", MAIN), ("square.vm", SQUARE), ("other.vm", other)])).unwrap_err();
+        assert_eq!(errors, vec![
+            "Duplicate symbol `back` exported by main.vm and other.vm",
+            "Duplicate symbol `square` exported by square.vm and other.vm"
+        ]);
+        let errors = link(&objects(&[("a.vm", ".import x\n.import y\njmp x\npush y\n")])).unwrap_err();
+        assert_eq!(errors, vec!["Undefined symbol `x` imported by a.vm", "Undefined symbol `y` imported by a.vm"]);
+    }
+
+    #[test]
+    fn objects_survive_encoding() {
+        for source in [MAIN, SQUARE] {
+            let object = object(source);
+            assert!(!object.relocs.is_empty());
+            assert_eq!(Object::decode(&object.encode()).unwrap(), object);
+        }
+        let with_debug = Object::from_assembly(assemble_object(SQUARE, None, &[]).unwrap(), Some("square.vm"));
+        assert_eq!(Object::decode(&with_debug.encode()).unwrap(), with_debug);
+    }
+
+    #[test]
+    fn addresses_only_known_after_linking() {
+        let error = |source: &str| assemble_object(source, None, &[]).unwrap_err().message;
+        assert_eq!(error(".import f\npush f / 2\n"), "`/` cannot be applied to an address that is only known after linking");
+        assert_eq!(error("here:\npush here * here\n"), "`*` cannot be applied to an address that is only known after linking");
+        assert_eq!(error(".data\nx: .bytes 1\ny: .bytes x\n"), "An address that is only known after linking does not fit in a byte");
+        assert_eq!(error(".export nothing\nhalt\n"), "Exported name `nothing` is not a label");
+        assert_eq!(crate::asm::assemble(".import f\njmp f\n").unwrap_err().message, "`f` is imported; compile with `-c` and link the object");
+        // Adding, subtracting and scaling are relocated.
+        let object = object(".import f\nstart:\npush 3 * f - start + 1\n");
+        assert_eq!(object.relocs, vec![
+            Reloc { place: Place::Code(0), target: Target::Symbol("f".to_string()), factor: 3 },
+            Reloc { place: Place::Code(0), target: Target::Code, factor: -1 }
+        ]);
+    }
+}
@@ -0,0 +1,183 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::vm::{InstError, Instruction, Word};
+
+// Hooks into a running `VM`. Every callback has an empty default, so an
+// observer only implements what it cares about. `stack` is always the live
+// part of the stack, bottom first, as it is at the time of the callback.
+pub trait Observer {
+    fn before_instruction(&mut self, _pc: usize, _inst: Instruction, _stack: &[Word]) {}
+    fn after_instruction(&mut self, _pc: usize, _inst: Instruction, _stack: &[Word]) {}
+    fn on_push(&mut self, _value: Word, _stack: &[Word]) {}
+    fn on_pop(&mut self, _value: Word, _stack: &[Word]) {}
+    fn on_jump(&mut self, _from: usize, _to: usize) {}
+    fn on_error(&mut self, _pc: usize, _inst: Instruction, _error: &InstError, _stack: &[Word]) {}
+}
+
+// Lets the host keep a handle on an observer after handing it to the VM,
+// e.g. to read a `CountingObserver` once the program has finished.
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn before_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+        self.borrow_mut().before_instruction(pc, inst, stack)
+    }
+    fn after_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+        self.borrow_mut().after_instruction(pc, inst, stack)
+    }
+    fn on_push(&mut self, value: Word, stack: &[Word]) {
+        self.borrow_mut().on_push(value, stack)
+    }
+    fn on_pop(&mut self, value: Word, stack: &[Word]) {
+        self.borrow_mut().on_pop(value, stack)
+    }
+    fn on_jump(&mut self, from: usize, to: usize) {
+        self.borrow_mut().on_jump(from, to)
+    }
+    fn on_error(&mut self, pc: usize, inst: Instruction, error: &InstError, stack: &[Word]) {
+        self.borrow_mut().on_error(pc, inst, error, stack)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CountingObserver {
+    pub instructions: usize,
+    pub per_opcode: HashMap<&'static str, usize>,
+    pub pushes: usize,
+    pub pops: usize,
+    pub jumps: usize,
+    pub errors: usize,
+    pub max_stack: usize
+}
+
+impl CountingObserver {
+    pub fn new() -> CountingObserver {
+        CountingObserver::default()
+    }
+    pub fn report(&self) -> String {
+        let mut ret = format!("instructions: {}\npushes: {}\npops: {}\njumps: {}\nerrors: {}\nmax stack: {}\n",
+            self.instructions, self.pushes, self.pops, self.jumps, self.errors, self.max_stack);
+        let mut ops: Vec<(&&str, &usize)> = self.per_opcode.iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (op, n) in ops {
+            ret += format!("\t{:<6} {}\n", op, n).as_str();
+        }
+        ret
+    }
+}
+
+impl Observer for CountingObserver {
+    fn after_instruction(&mut self, _pc: usize, inst: Instruction, stack: &[Word]) {
+        self.instructions += 1;
+        *self.per_opcode.entry(inst.mnemonic()).or_insert(0) += 1;
+        self.max_stack = self.max_stack.max(stack.len());
+    }
+    fn on_push(&mut self, _value: Word, stack: &[Word]) {
+        self.pushes += 1;
+        self.max_stack = self.max_stack.max(stack.len());
+    }
+    fn on_pop(&mut self, _value: Word, _stack: &[Word]) {
+        self.pops += 1;
+    }
+    fn on_jump(&mut self, _from: usize, _to: usize) {
+        self.jumps += 1;
+    }
+    fn on_error(&mut self, _pc: usize, _inst: Instruction, _error: &InstError, _stack: &[Word]) {
+        self.errors += 1;
+    }
+}
+
+// Prints every event to stderr so it does not interleave with the program's
+// own output on stdout.
+#[derive(Debug, Default)]
+pub struct PrintingObserver;
+
+impl Observer for PrintingObserver {
+    fn before_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+        eprintln!("[{:>4}] {:<12} {:?}", pc, inst.to_string(), stack);
+    }
+    fn on_push(&mut self, value: Word, _stack: &[Word]) {
+        eprintln!("       push {}", value);
+    }
+    fn on_pop(&mut self, value: Word, _stack: &[Word]) {
+        eprintln!("       pop {}", value);
+    }
+    fn on_jump(&mut self, from: usize, to: usize) {
+        eprintln!("       jump {} -> {}", from, to);
+    }
+    fn on_error(&mut self, pc: usize, inst: Instruction, error: &InstError, _stack: &[Word]) {
+        eprintln!("       error at {} ({}): {}", pc, inst, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::vm::{RunOutcome, VM};
+
+    // Writes down every callback.
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Observer for Recorder {
+        fn before_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+            self.0.push(format!("before {} {} {:?}", pc, inst, stack));
+        }
+        fn after_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+            self.0.push(format!("after {} {} {:?}", pc, inst, stack));
+        }
+        fn on_push(&mut self, value: Word, stack: &[Word]) {
+            self.0.push(format!("push {} {:?}", value, stack));
+        }
+        fn on_pop(&mut self, value: Word, stack: &[Word]) {
+            self.0.push(format!("pop {} {:?}", value, stack));
+        }
+        fn on_jump(&mut self, from: usize, to: usize) {
+            self.0.push(format!("jump {} {}", from, to));
+        }
+        fn on_error(&mut self, pc: usize, inst: Instruction, error: &InstError, stack: &[Word]) {
+            self.0.push(format!("error {} {} {} {:?}", pc, inst, error, stack));
+        }
+    }
+
+    fn observed<T: Observer + 'static>(source: &str, observer: &Rc<RefCell<T>>) -> RunOutcome {
+        let mut vm = VM::init();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.load_program(assemble(source).unwrap().program);
+        vm.add_observer(Box::new(observer.clone()));
+        vm.run_program()
+    }
+
+    #[test]
+    fn callbacks_come_in_execution_order() {
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        observed("push 1\npush 2\nadd\njmp end\npush 9\nend:\npush 0\ndiv\n", &recorder);
+        assert_eq!(recorder.borrow().0, [
+            "before 0 push 1 []", "push 1 [1]", "after 0 push 1 [1]",
+            "before 1 push 2 [1]", "push 2 [1, 2]", "after 1 push 2 [1, 2]",
+            "before 2 add [1, 2]", "pop 2 [1]", "pop 1 []", "push 3 [3]", "after 2 add [3]",
+            "before 3 jmp 5 [3]", "jump 3 5", "after 3 jmp 5 [3]",
+            "before 5 push 0 [3]", "push 0 [3, 0]", "after 5 push 0 [3, 0]",
+            "before 6 div [3, 0]", "error 6 div division by zero [3, 0]"
+        ]);
+    }
+
+    #[test]
+    fn every_observer_sees_every_event() {
+        let (first, second) = (Rc::new(RefCell::new(Recorder::default())), Rc::new(RefCell::new(Recorder::default())));
+        let mut vm = VM::init();
+        vm.load_program(assemble("push 1\nhalt\n").unwrap().program);
+        vm.add_observer(Box::new(first.clone()));
+        vm.add_observer(Box::new(second.clone()));
+        assert_eq!(vm.run_program(), RunOutcome::Halted(1));
+        assert_eq!(first.borrow().0, second.borrow().0);
+        assert_eq!(vm.clear_observers().len(), 2);
+    }
+
+    #[test]
+    fn counting_observer_report() {
+        let counter = Rc::new(RefCell::new(CountingObserver::new()));
+        observed("push 3\nloop:\npush 1\nsub\ndup 0\npush 0\nneq\njnz loop\n", &counter);
+        assert_eq!(counter.borrow().report(), "instructions: 19\npushes: 16\npops: 14\njumps: 2\nerrors: 0\nmax stack: 3\n\tpush   7\n\tdup    3\n\tjnz    3\n\tneq    3\n\tsub    3\n");
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::asm::Assembly;
+use crate::observer::Observer;
+use crate::vm::{Instruction, Word};
+
+struct Block {
+    start: usize,
+    end: usize,
+    entries: u64,
+    time: Duration
+}
+
+// Counts executions per pc and per opcode and times basic blocks. Blocks
+// start at pc 0, at every jump target and after every jump or halt; the
+// clock is read once per block entry, not per instruction.
+pub struct Profiler {
+    program: Vec<Instruction>,
+    counts: Vec<u64>,
+    per_opcode: HashMap<&'static str, u64>,
+    blocks: Vec<Block>,
+    block_of: Vec<usize>,
+    leader: Vec<bool>,
+    current: Option<(usize, Instant)>,
+    source: Option<Vec<(String, usize)>>
+}
+
+impl Profiler {
+    pub fn new(program: &[Instruction]) -> Profiler {
+        let mut leader = vec![false; program.len()];
+        if !program.is_empty() {
+            leader[0] = true;
+        }
+        for (pc, inst) in program.iter().enumerate() {
+            if let Instruction::JMP(to) | Instruction::JNZ(to) = inst {
+                if let Some(l) = leader.get_mut(*to as usize) {
+                    *l = true;
+                }
+            }
+            if let Instruction::JMP(_) | Instruction::JNZ(_) | Instruction::HALT = inst {
+                if let Some(l) = leader.get_mut(pc + 1) {
+                    *l = true;
+                }
+            }
+        }
+        let mut blocks: Vec<Block> = vec![];
+        let mut block_of = vec![0; program.len()];
+        for pc in 0..program.len() {
+            if leader[pc] {
+                blocks.push(Block { start: pc, end: pc, entries: 0, time: Duration::ZERO });
+            }
+            let last = blocks.len() - 1;
+            blocks[last].end = pc;
+            block_of[pc] = last;
+        }
+        Profiler {
+            program: program.to_vec(),
+            counts: vec![0; program.len()],
+            per_opcode: HashMap::new(),
+            blocks,
+            block_of,
+            leader,
+            current: None,
+            source: None
+        }
+    }
+    // Maps pcs back to source lines in the output, `path` being the file the
+    // assembly was read from.
+    pub fn with_source(mut self, path: &str, assembly: &Assembly) -> Profiler {
+        self.source = Some((0..assembly.program.len()).map(|pc| (assembly.file(pc, path).to_string(), assembly.lines[pc])).collect());
+        self
+    }
+
+    // Closes the block that was running when the program stopped.
+    pub fn finish(&mut self) {
+        if let Some((block, since)) = self.current.take() {
+            self.blocks[block].time += since.elapsed();
+        }
+    }
+
+    fn location(&self, pc: usize) -> String {
+        match &self.source {
+            Some(source) => format!("{}:{}", source[pc].0, source[pc].1),
+            None => format!("pc {}", pc)
+        }
+    }
+    fn block_name(&self, block: &Block) -> String {
+        match &self.source {
+            Some(source) if source[block.start].0 == source[block.end].0 => format!("{}:{}-{}", source[block.start].0, source[block.start].1, source[block.end].1),
+            Some(source) => format!("{}:{}-{}:{}", source[block.start].0, source[block.start].1, source[block.end].0, source[block.end].1),
+            None => format!("pc {}-{}", block.start, block.end)
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let total: u64 = self.counts.iter().sum();
+        let percent = |n: u64| if total == 0 { 0.0 } else { n as f64 * 100.0 / total as f64 };
+        let mut ret = format!("{} instructions executed\n\nHot instructions:\n", total);
+        let mut pcs: Vec<usize> = (0..self.counts.len()).filter(|pc| self.counts[*pc] > 0).collect();
+        pcs.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        for pc in pcs {
+            ret += format!("\t{:>10} {:>6.2}%  {:<16} {}\n", self.counts[pc], percent(self.counts[pc]), self.location(pc), self.program[pc]).as_str();
+        }
+
+        ret += "\nOpcodes:\n";
+        let mut ops: Vec<(&&str, &u64)> = self.per_opcode.iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (op, n) in ops {
+            ret += format!("\t{:>10} {:>6.2}%  {}\n", n, percent(*n), op).as_str();
+        }
+
+        ret += "\nBasic blocks:\n";
+        let total_time: Duration = self.blocks.iter().map(|b| b.time).sum();
+        let mut blocks: Vec<&Block> = self.blocks.iter().filter(|b| b.entries > 0).collect();
+        blocks.sort_by(|a, b| b.time.cmp(&a.time).then(a.start.cmp(&b.start)));
+        for block in blocks {
+            let share = if total_time.is_zero() { 0.0 } else { block.time.as_secs_f64() * 100.0 / total_time.as_secs_f64() };
+            ret += format!("\t{:>12?} {:>6.2}%  {:>8} entries  {}\n", block.time, share, block.entries, self.block_name(block)).as_str();
+        }
+        ret
+    }
+
+    // Folded stacks (`frame;frame count`) for flamegraph tools, weighted by
+    // execution count: one frame for the block and one for the instruction.
+    pub fn folded(&self) -> String {
+        let mut ret = String::new();
+        for (pc, count) in self.counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let block = &self.blocks[self.block_of[pc]];
+            ret += format!("lvm;{};{} {} {}\n", self.block_name(block), self.location(pc), self.program[pc], count).as_str();
+        }
+        ret
+    }
+}
+
+impl Observer for Profiler {
+    fn before_instruction(&mut self, pc: usize, inst: Instruction, _stack: &[Word]) {
+        if pc >= self.counts.len() {
+            return;
+        }
+        if self.leader[pc] || self.current.is_none() {
+            let now = Instant::now();
+            if let Some((block, since)) = self.current {
+                self.blocks[block].time += now - since;
+            }
+            let block = self.block_of[pc];
+            self.blocks[block].entries += 1;
+            self.current = Some((block, now));
+        }
+        self.counts[pc] += 1;
+        *self.per_opcode.entry(inst.mnemonic()).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::asm::assemble;
+    use crate::vm::VM;
+
+    const PROGRAM: &str = "push 3\nloop:\npush 1\nsub\ndup 0\npush 0\nneq\njnz loop\npush 7\nhalt\n";
+
+    fn profile(source: &str, path: Option<&str>) -> Profiler {
+        let assembly = assemble(source).unwrap();
+        let mut profiler = Profiler::new(&assembly.program);
+        if let Some(path) = path {
+            profiler = profiler.with_source(path, &assembly);
+        }
+        let profiler = Rc::new(RefCell::new(profiler));
+        let mut vm = VM::init();
+        vm.load_program(assembly.program);
+        vm.add_observer(Box::new(profiler.clone()));
+        vm.run_program();
+        drop(vm);
+        let mut profiler = Rc::try_unwrap(profiler).ok().unwrap().into_inner();
+        profiler.finish();
+        profiler
+    }
+
+    #[test]
+    fn counts_instructions_and_opcodes() {
+        let profiler = profile(PROGRAM, None);
+        assert_eq!(profiler.counts, [1, 3, 3, 3, 3, 3, 3, 1, 1]);
+        assert_eq!(profiler.per_opcode.get("push"), Some(&8));
+        assert_eq!(profiler.per_opcode.get("halt"), Some(&1));
+        let report = profiler.report();
+        assert!(report.starts_with("21 instructions executed\n\nHot instructions:\n\t         3  14.29%  pc 1             push 1\n"), "{}", report);
+        assert!(report.contains("\nOpcodes:\n\t         8  38.10%  push\n\t         3  14.29%  dup\n"), "{}", report);
+    }
+
+    #[test]
+    fn splits_basic_blocks_at_jumps() {
+        let profiler = profile(PROGRAM, None);
+        let blocks: Vec<(usize, usize, u64)> = profiler.blocks.iter().map(|b| (b.start, b.end, b.entries)).collect();
+        assert_eq!(blocks, [(0, 0, 1), (1, 6, 3), (7, 8, 1)]);
+        assert!(profiler.report().contains("       3 entries  pc 1-6\n"));
+        // An instruction after `halt` starts a block of its own.
+        let profiler = profile("push 1\njnz skip\nhalt\nskip:\npush 2\n", None);
+        let blocks: Vec<(usize, usize, u64)> = profiler.blocks.iter().map(|b| (b.start, b.end, b.entries)).collect();
+        assert_eq!(blocks, [(0, 1, 1), (2, 2, 0), (3, 3, 1)]);
+    }
+
+    #[test]
+    fn folded_stacks_are_weighted_by_count() {
+        assert_eq!(profile("push 1\npush 2\nadd\n", None).folded(), "lvm;pc 0-2;pc 0 push 1 1\nlvm;pc 0-2;pc 1 push 2 1\nlvm;pc 0-2;pc 2 add 1\n");
+        let folded = profile(PROGRAM, Some("count.vm")).folded();
+        assert_eq!(folded.lines().nth(1), Some("lvm;count.vm:3-8;count.vm:3 push 1 3"));
+        assert_eq!(folded.lines().last(), Some("lvm;count.vm:9-10;count.vm:10 halt 1"));
+    }
+}
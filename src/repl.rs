@@ -0,0 +1,236 @@
+use std::io::{BufRead, Write};
+use crate::asm::{assemble_object, Assembly};
+use crate::obj::{Place, Target};
+use crate::vm::{StepStatus, Word, VM};
+
+const HELP: &str = "Enter assembly, one instruction per line. Meta commands:
+\t:reset          start over with an empty VM
+\t:load <file>    assemble and run a .vm file in this session
+\t:history        show the instructions entered so far
+\t:save <file>    write the session to a .vm file
+\t:quit           leave the REPL";
+
+// Every accepted line is appended to the VM's program and executed straight
+// away, so jumps can only go back to instructions entered earlier.
+pub struct Repl {
+    vm: VM,
+    history: Vec<String>
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl { vm: VM::init(), history: vec![] }
+    }
+
+    pub fn run<R: BufRead>(&mut self, input: R) {
+        println!("lvm repl. Type :help for meta commands.");
+        let mut lines = input.lines();
+        loop {
+            print!("> ");
+            let _ = std::io::stdout().flush();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break
+            };
+            if !self.command(line.trim()) {
+                break;
+            }
+        }
+    }
+
+    // Handles one line of input, returns false once the user quits.
+    pub fn command(&mut self, line: &str) -> bool {
+        if line.is_empty() {
+            return true;
+        }
+        if let Some(meta) = line.strip_prefix(':') {
+            let (name, arg) = meta.split_once(' ').map(|(n, a)| (n, a.trim())).unwrap_or((meta, ""));
+            match (name, arg) {
+                ("help", _) => println!("{}", HELP),
+                ("quit", _) | ("q", _) => return false,
+                ("reset", _) => {
+                    *self = Repl::new();
+                    println!("Reset.");
+                }
+                ("history", _) => {
+                    for (n, l) in self.history.iter().enumerate() {
+                        println!("{:>4}: {}", n, l);
+                    }
+                }
+                ("load", path) if !path.is_empty() => match std::fs::read_to_string(path) {
+                    Ok(source) => self.eval(&source, Some(path)),
+                    Err(e) => println!("Could not read {}: {}", path, e)
+                },
+                ("save", path) if !path.is_empty() => {
+                    let mut source = self.history.join("\n");
+                    source += "\n";
+                    match std::fs::write(path, source) {
+                        Ok(()) => println!("Saved {} lines to {}.", self.history.len(), path),
+                        Err(e) => println!("Could not write {}: {}", path, e)
+                    }
+                }
+                _ => println!("Unknown meta command `{}`. Type :help for the list.", line)
+            }
+            return true;
+        }
+        self.eval(line, None);
+        true
+    }
+
+    // `path` is where `source` was read from, for resolving its includes.
+    fn eval(&mut self, source: &str, path: Option<&str>) {
+        if self.vm.exit_code().is_some() {
+            println!("The program has halted; use :reset to start over.");
+            return;
+        }
+        // Assembled as an object, so the labels can be moved to where the
+        // code ends up behind the instructions entered earlier.
+        let mut assembly = match assemble_object(source, path, &[]) {
+            Ok(assembly) => assembly,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if let Some(name) = assembly.imports.first() {
+            println!("`{}` is imported, but the REPL does not link objects.", name);
+            return;
+        }
+        let start = self.vm.program().len();
+        relocate(&mut assembly, start);
+        // Data labels are offsets into this source's own data, so a session
+        // can only have one data segment.
+        let new_data = !assembly.data.is_empty();
+        if new_data {
+            if !self.vm.data().is_empty() {
+                println!("The session already has a data segment; use :reset to start over.");
+                return;
+            }
+            self.vm.set_data(assembly.data);
+        }
+        let stack = self.vm.stack().to_vec();
+        self.vm.load_program(assembly.program);
+        let mut status = StepStatus::Running;
+        while status == StepStatus::Running {
+            status = self.vm.step();
+        }
+        match status {
+            StepStatus::Error(e) => {
+                // Undo what was just entered so the session stays replayable.
+                print!("{}", e.report());
+                self.vm.truncate_program(start);
+                self.vm.set_stack(&stack);
+                if new_data {
+                    self.vm.set_data(vec![]);
+                }
+            }
+            _ => {
+                self.history.extend(source.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()));
+                if let Some(code) = self.vm.exit_code() {
+                    println!("Program halted with code {}.", code);
+                }
+            }
+        }
+        println!("stack: {:?}", self.vm.stack());
+    }
+}
+
+// Moves code assembled to start at index 0 to `start`. The data segment
+// always starts at 0, since a session only has one.
+fn relocate(assembly: &mut Assembly, start: usize) {
+    for r in assembly.relocs.iter().filter(|r| r.target == Target::Code) {
+        let delta = (start as Word).wrapping_mul(r.factor as Word);
+        match r.place {
+            Place::Code(i) => {
+                let inst = assembly.program[i];
+                assembly.program[i] = inst.with_operand(inst.operand().unwrap_or(0).wrapping_add(delta));
+            }
+            Place::Data(o) => {
+                let mut word = [0u8; 8];
+                word.copy_from_slice(&assembly.data[o..o + 8]);
+                assembly.data[o..o + 8].copy_from_slice(&Word::from_le_bytes(word).wrapping_add(delta).to_le_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::asm::assemble;
+    use crate::vm::RunOutcome;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn printed(output: &Output) -> String {
+        String::from_utf8(output.0.borrow().clone()).unwrap()
+    }
+
+    const LOOP: &str = "top:\n    push 1\n    sub\n    dup 0\n    print\n    dup 0\n    push 0\n    neq\n    jnz top\n.loop 2\n    print\n.endloop\n";
+
+    #[test]
+    fn labels_follow_earlier_input() {
+        let output = Output::default();
+        let mut repl = Repl::new();
+        repl.vm.set_output(Box::new(output.clone()));
+        repl.command("push 5");
+        repl.command("push 3");
+        repl.eval(LOOP, None);
+        assert_eq!(printed(&output), "2\n1\n0\n2\n1\n");
+        assert_eq!(repl.vm.stack(), &[5, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn saved_sessions_run_like_the_session() {
+        let mut repl = Repl::new();
+        repl.vm.set_output(Box::new(std::io::sink()));
+        repl.command("push 5");
+        repl.command("push 3");
+        repl.eval(LOOP, None);
+        repl.command("bogus");
+        let path = std::env::temp_dir().join(format!("lvm-repl-{}.vm", std::process::id()));
+        repl.command(&format!(":save {}", path.display()));
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let output = Output::default();
+        let mut vm = VM::init();
+        vm.set_output(Box::new(output.clone()));
+        vm.load_program(assemble(&saved).unwrap().program);
+        assert_eq!(vm.run_program(), RunOutcome::Finished);
+        assert_eq!(printed(&output), "2\n1\n0\n2\n1\n");
+        assert_eq!(vm.stack(), repl.vm.stack());
+    }
+
+    #[test]
+    fn failed_input_is_undone() {
+        let mut repl = Repl::new();
+        repl.vm.set_output(Box::new(std::io::sink()));
+        repl.command("push 1");
+        repl.command("push 2");
+        repl.eval("push 0\ndiv\n", None);
+        assert_eq!(repl.vm.stack(), &[1, 2]);
+        assert_eq!(repl.vm.program().len(), 2);
+        assert_eq!(repl.history, ["push 1", "push 2"]);
+        repl.eval(".import f\njmp f\n", None);
+        assert_eq!(repl.vm.program().len(), 2);
+    }
+}
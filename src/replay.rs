@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use crate::json::Json;
+use crate::vm::{RunOutcome, VM, Word};
+
+const REPLAY_VERSION: u64 = 1;
+
+// Program output is both shown and kept for the recording.
+#[derive(Clone, Default)]
+struct Tee(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        std::io::stdout().write_all(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+// Everything needed to check that a run can be reproduced. No instruction
+// reads external input (stdin, time, randomness or host calls) yet, so the
+// observable behaviour of a run is fixed by the program and is captured here
+// as its output, outcome and final stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    pub program: u64,
+    pub output: String,
+    pub outcome: Json,
+    pub stack: Vec<Word>
+}
+
+impl Recording {
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("version", Json::from(REPLAY_VERSION)),
+            ("program", Json::Str(format!("{:016x}", self.program))),
+            ("output", Json::Str(self.output.clone())),
+            ("outcome", self.outcome.clone()),
+            ("stack", Json::Array(self.stack.iter().map(|w| Json::from(*w)).collect()))
+        ])
+    }
+    pub fn from_json(json: &Json) -> Result<Recording, String> {
+        match json.get("version").and_then(|v| v.as_u64()) {
+            Some(REPLAY_VERSION) => {}
+            Some(v) => return Err(format!("Unsupported replay log version {}.", v)),
+            None => return Err("Missing replay log version.".to_string())
+        }
+        let field = |name: &str| json.get(name).ok_or(format!("Missing `{}` in replay log.", name));
+        let program = field("program")?.as_str().and_then(|p| u64::from_str_radix(p, 16).ok()).ok_or("Malformed program fingerprint.")?;
+        let output = field("output")?.as_str().ok_or("Malformed output.")?.to_string();
+        let stack = field("stack")?.as_array().ok_or("Malformed stack.")?
+            .iter().map(|w| w.as_u64().ok_or("Malformed stack word.".to_string())).collect::<Result<Vec<Word>, String>>()?;
+        Ok(Recording { program, output, outcome: field("outcome")?.clone(), stack })
+    }
+    pub fn load(path: &str) -> std::io::Result<Recording> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid replay log: {}", e));
+        let json = Json::parse(&std::fs::read_to_string(path)?).map_err(invalid)?;
+        Recording::from_json(&json).map_err(invalid)
+    }
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, format!("{}\n", self.to_json()))
+    }
+}
+
+fn run_captured(vm: &mut VM) -> (RunOutcome, Recording) {
+    let output = Tee::default();
+    vm.set_output(Box::new(output.clone()));
+    let outcome = vm.run_program();
+    vm.set_output(Box::new(std::io::stdout()));
+    let recording = Recording {
+        program: vm.fingerprint(),
+        output: String::from_utf8_lossy(&output.0.borrow()).to_string(),
+        outcome: outcome.to_json(),
+        stack: vm.stack().to_vec()
+    };
+    (outcome, recording)
+}
+
+pub fn record(vm: &mut VM) -> (RunOutcome, Recording) {
+    run_captured(vm)
+}
+
+// Runs the program again and lists every way in which the run differs from
+// `expected`; an empty list means the replay matched.
+pub fn replay(vm: &mut VM, expected: &Recording) -> (RunOutcome, Vec<String>) {
+    let mut mismatches = vec![];
+    if vm.fingerprint() != expected.program {
+        mismatches.push(format!("program fingerprint {:016x} does not match the recorded {:016x}", vm.fingerprint(), expected.program));
+    }
+    let (outcome, actual) = run_captured(vm);
+    if actual.output != expected.output {
+        let at = actual.output.chars().zip(expected.output.chars()).take_while(|(a, b)| a == b).count();
+        mismatches.push(format!("output differs from the recording at character {}", at));
+    }
+    if actual.outcome != expected.outcome {
+        mismatches.push(format!("outcome {} differs from the recorded {}", actual.outcome, expected.outcome));
+    }
+    if actual.stack != expected.stack {
+        mismatches.push(format!("final stack {:?} differs from the recorded {:?}", actual.stack, expected.stack));
+    }
+    (outcome, mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn vm(source: &str) -> VM {
+        let assembly = assemble(source).unwrap();
+        let mut vm = VM::init();
+        vm.load_program(assembly.program);
+        vm.set_data(assembly.data);
+        vm
+    }
+
+    const PROGRAM: &str = "push 6\nprint\npush 7\nprint\nmul\nhalt\n";
+
+    #[test]
+    fn recordings_replay_and_round_trip() {
+        let (outcome, recording) = record(&mut vm(PROGRAM));
+        assert_eq!(outcome, RunOutcome::Halted(42));
+        assert_eq!((recording.output.as_str(), recording.stack.as_slice()), ("6\n7\n", &[][..]));
+        assert_eq!(Recording::from_json(&Json::parse(&recording.to_json().to_string()).unwrap()), Ok(recording.clone()));
+        assert_eq!(replay(&mut vm(PROGRAM), &recording), (RunOutcome::Halted(42), vec![]));
+    }
+
+    #[test]
+    fn replays_report_every_mismatch() {
+        let (_outcome, recording) = record(&mut vm(PROGRAM));
+        let mut edited = recording.clone();
+        edited.output = "6\n8\n".to_string();
+        edited.stack = vec![1];
+        edited.outcome = RunOutcome::Finished.to_json();
+        let (_outcome, mismatches) = replay(&mut vm(PROGRAM), &edited);
+        assert_eq!(mismatches, [
+            "output differs from the recording at character 2",
+            "outcome {\"kind\":\"halted\",\"code\":42} differs from the recorded {\"kind\":\"finished\"}",
+            "final stack [] differs from the recorded [1]"
+        ]);
+        // Another program, even one with the same behaviour.
+        let (_outcome, mismatches) = replay(&mut vm("push 6\nprint\npush 7\nprint\nmul\nblind\nhalt\n"), &recording);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].starts_with("program fingerprint "), "{}", mismatches[0]);
+        let (_outcome, mismatches) = replay(&mut vm(&format!(".data\nx: .bytes 1\n.text\n{}", PROGRAM)), &recording);
+        assert!(mismatches[0].starts_with("program fingerprint "));
+    }
+
+    #[test]
+    fn malformed_logs_are_rejected() {
+        let (_outcome, recording) = record(&mut vm(PROGRAM));
+        let with = |name: &str, value: Json| {
+            let mut json = recording.to_json();
+            if let Json::Object(fields) = &mut json {
+                fields.retain(|(k, _v)| k != name);
+                fields.push((name.to_string(), value));
+            }
+            Recording::from_json(&json).unwrap_err()
+        };
+        assert_eq!(with("version", Json::Int(2)), "Unsupported replay log version 2.");
+        assert_eq!(with("version", Json::Null), "Missing replay log version.");
+        assert_eq!(with("program", Json::str("xyz")), "Malformed program fingerprint.");
+        assert_eq!(with("output", Json::Int(1)), "Malformed output.");
+        assert_eq!(with("stack", Json::Array(vec![Json::Int(-1)])), "Malformed stack word.");
+        let mut json = recording.to_json();
+        if let Json::Object(fields) = &mut json {
+            fields.retain(|(k, _v)| k != "outcome");
+        }
+        assert_eq!(Recording::from_json(&json).unwrap_err(), "Missing `outcome` in replay log.");
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use crate::json::Json;
+use crate::observer::Observer;
+use crate::vm::{InstError, Instruction, Word};
+
+// Writes one JSON object per executed instruction, e.g.
+// {"step":3,"pc":2,"instruction":{"op":"add"},"depth":1,"top":[10]}
+// `top` holds up to `top_n` values, top of stack first. Records describe the
+// state after the instruction ran; a failing instruction gets an "error"
+// field and the stack as it was left.
+pub struct JsonTracer {
+    out: BufWriter<File>,
+    step: u64,
+    top_n: usize,
+    range: Option<Range<usize>>,
+    max_bytes: Option<u64>,
+    written: u64,
+    stopped: bool
+}
+
+impl JsonTracer {
+    pub fn create(path: &str) -> std::io::Result<JsonTracer> {
+        Ok(JsonTracer {
+            out: BufWriter::new(File::create(path)?),
+            step: 0,
+            top_n: 4,
+            range: None,
+            max_bytes: None,
+            written: 0,
+            stopped: false
+        })
+    }
+    pub fn top_n(mut self, n: usize) -> JsonTracer {
+        self.top_n = n;
+        self
+    }
+    // Only instructions whose pc falls in `range` are written; steps are still
+    // numbered across the whole run so traces of different ranges line up.
+    pub fn pc_range(mut self, range: Range<usize>) -> JsonTracer {
+        self.range = Some(range);
+        self
+    }
+    // Stops tracing before the file would grow past `max` bytes.
+    pub fn max_bytes(mut self, max: u64) -> JsonTracer {
+        self.max_bytes = Some(max);
+        self
+    }
+
+    fn record(&mut self, pc: usize, inst: Instruction, stack: &[Word], error: Option<&InstError>) {
+        self.step += 1;
+        if self.stopped || self.range.as_ref().is_some_and(|r| !r.contains(&pc)) {
+            return;
+        }
+        let mut fields = vec![
+            ("step", Json::from(self.step)),
+            ("pc", Json::from(pc)),
+            ("instruction", inst.to_json()),
+            ("depth", Json::from(stack.len())),
+            ("top", Json::Array(stack.iter().rev().take(self.top_n).map(|w| Json::from(*w)).collect()))
+        ];
+        if let Some(e) = error {
+            fields.push(("error", Json::str(e.code())));
+            fields.push(("message", Json::Str(e.to_string())));
+        }
+        let line = format!("{}\n", Json::object(fields));
+        if self.max_bytes.is_some_and(|max| self.written + line.len() as u64 > max) {
+            self.stopped = true;
+            return;
+        }
+        if let Err(e) = self.out.write_all(line.as_bytes()) {
+            eprintln!("Could not write trace: {}", e);
+            self.stopped = true;
+            return;
+        }
+        self.written += line.len() as u64;
+    }
+}
+
+impl Observer for JsonTracer {
+    fn after_instruction(&mut self, pc: usize, inst: Instruction, stack: &[Word]) {
+        self.record(pc, inst, stack, None);
+    }
+    fn on_error(&mut self, pc: usize, inst: Instruction, error: &InstError, stack: &[Word]) {
+        self.record(pc, inst, stack, Some(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::vm::VM;
+
+    const PROGRAM: &str = "push 1\npush 2\npush 3\nadd\nadd\npush 0\ndiv\n";
+
+    // Runs PROGRAM under the tracer `configure` returns and reads the trace.
+    fn trace(name: &str, configure: fn(JsonTracer) -> JsonTracer) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("lvm-trace-{}-{}.jsonl", std::process::id(), name));
+        let path = path.to_str().unwrap();
+        let mut vm = VM::init();
+        vm.load_program(assemble(PROGRAM).unwrap().program);
+        vm.add_observer(Box::new(configure(JsonTracer::create(path).unwrap())));
+        vm.run_program();
+        // Dropping the tracer flushes it.
+        drop(vm.clear_observers());
+        let text = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn records_every_instruction() {
+        let lines = trace("all", |t| t);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "{\"step\":1,\"pc\":0,\"instruction\":{\"op\":\"push\",\"operand\":1},\"depth\":1,\"top\":[1]}");
+        assert_eq!(lines[3], "{\"step\":4,\"pc\":3,\"instruction\":{\"op\":\"add\"},\"depth\":2,\"top\":[5,1]}");
+        assert_eq!(lines[6], "{\"step\":7,\"pc\":6,\"instruction\":{\"op\":\"div\"},\"depth\":2,\"top\":[0,6],\"error\":\"division_by_zero\",\"message\":\"division by zero\"}");
+        assert!(trace("top", |t| t.top_n(1))[2].ends_with("\"depth\":3,\"top\":[3]}"));
+    }
+
+    #[test]
+    fn filters_by_pc_range() {
+        let lines = trace("range", |t| t.pc_range(3..5));
+        assert_eq!(lines.len(), 2);
+        // Steps keep their numbers from the whole run.
+        assert!(lines[0].starts_with("{\"step\":4,\"pc\":3,"));
+        assert!(lines[1].starts_with("{\"step\":5,\"pc\":4,"));
+        assert!(trace("empty", |t| t.pc_range(7..9)).is_empty());
+    }
+
+    #[test]
+    fn stops_before_max_bytes() {
+        let all = trace("unlimited", |t| t);
+        assert_eq!(all[0].len() + all[1].len() + 2, 158);
+        assert_eq!(trace("limited", |t| t.max_bytes(158)), all[..2]);
+        // The third record does not fit; the fourth, shorter one would,
+        // but the trace has stopped.
+        assert_eq!((all[2].len() + 1, all[3].len() + 1), (82, 67));
+        assert_eq!(trace("stopped", |t| t.max_bytes(158 + 67)), all[..2]);
+        assert!(trace("none", |t| t.max_bytes(10)).is_empty());
+    }
+}
@@ -23,14 +23,13 @@ pub mod io {
         }
     }
 }
+#[macro_export]
 macro_rules! cout {
     
     [$x:expr] => {
-        crate::io::io::Out::print($x)
+        $crate::io::io::Out::print($x)
     };
     [] => {
-        crate::io::io::Out::print("")
+        $crate::io::io::Out::print("")
     }
-}
-
-pub(crate) use cout;
\ No newline at end of file
+}
\ No newline at end of file
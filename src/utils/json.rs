@@ -0,0 +1,357 @@
+use std::fmt;
+
+// Just enough JSON for the tooling around the VM. Integers are kept apart
+// from floats so that full 64 bit words survive a round trip.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+    pub fn str(s: &str) -> Json {
+        Json::Str(s.to_string())
+    }
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: text.chars().collect(), pos: 0 };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != parser.chars.len() {
+            return Err(format!("Trailing characters at {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _v)| k == key).map(|(_k, v)| v),
+            _ => None
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None
+        }
+    }
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            Json::Int(n) => Some(*n),
+            Json::Float(n) if n.fract() == 0.0 => Some(*n as i128),
+            _ => None
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_i128().and_then(|n| u64::try_from(n).ok())
+    }
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_i128().and_then(|n| usize::try_from(n).ok())
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize
+}
+
+impl Parser {
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected `{}` at {}", c, self.pos))
+        }
+    }
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for c in word.chars() {
+            if self.peek() != Some(c) {
+                return Err(format!("Unexpected character at {}", self.pos));
+            }
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_ws();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                self.skip_ws();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(format!("Expected `,` or `}}` at {}", self.pos))
+                    }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                self.skip_ws();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(format!("Expected `,` or `]` at {}", self.pos))
+                    }
+                }
+            }
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(format!("Unexpected character at {}", self.pos))
+        }
+    }
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        if let Ok(n) = text.parse::<i128>() {
+            return Ok(Json::Int(n));
+        }
+        text.parse::<f64>().map(Json::Float).map_err(|_e| format!("Malformed number at {}", start))
+    }
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some('"') {
+            return Err(format!("Expected string at {}", self.pos));
+        }
+        self.pos += 1;
+        let mut ret = String::new();
+        loop {
+            match self.peek() {
+                None => return Err("Unterminated string".to_string()),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(ret);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    let c = self.peek().ok_or("Unterminated string")?;
+                    self.pos += 1;
+                    match c {
+                        'n' => ret.push('\n'),
+                        't' => ret.push('\t'),
+                        'r' => ret.push('\r'),
+                        'b' => ret.push('\u{8}'),
+                        'f' => ret.push('\u{c}'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // Surrogate pair. An unpaired half becomes U+FFFD.
+                            if (0xd800..0xdc00).contains(&code) && self.chars.get(self.pos) == Some(&'\\') && self.chars.get(self.pos + 1) == Some(&'u') {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                match (0xdc00..0xe000).contains(&low) {
+                                    true => code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00),
+                                    false => {
+                                        ret.push('\u{fffd}');
+                                        code = low;
+                                    }
+                                }
+                            }
+                            ret.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => ret.push(c)
+                    }
+                }
+                Some(c) => {
+                    ret.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        if self.pos + 4 > self.chars.len() {
+            return Err("Truncated unicode escape".to_string());
+        }
+        let text: String = self.chars[self.pos..self.pos + 4].iter().collect();
+        self.pos += 4;
+        u32::from_str_radix(&text, 16).map_err(|_e| format!("Malformed unicode escape at {}", self.pos - 4))
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Int(n as i128)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Int(n as i128)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+// Always renders compactly, which is what both JSON lines and the
+// protocol servers want.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::Float(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Float(_) => write!(f, "null"),
+            Json::Str(s) => write_str(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let value = Json::parse(" {\"a\": [1, -2, 3.5, true, false, null], \"b\": {}, \"c\": [] } ").unwrap();
+        assert_eq!(value, Json::object(vec![
+            ("a", Json::Array(vec![Json::Int(1), Json::Int(-2), Json::Float(3.5), Json::Bool(true), Json::Bool(false), Json::Null])),
+            ("b", Json::Object(vec![])),
+            ("c", Json::Array(vec![]))
+        ]));
+        assert_eq!(value.get("a").and_then(|a| a.as_array()).map(|a| a.len()), Some(6));
+        assert_eq!(value.get("missing"), None);
+    }
+
+    #[test]
+    fn numbers_keep_full_words() {
+        assert_eq!(Json::parse("18446744073709551615").unwrap().as_u64(), Some(u64::MAX));
+        assert_eq!(Json::parse("-1").unwrap().as_u64(), None);
+        assert_eq!(Json::parse("1e3").unwrap(), Json::Float(1000.0));
+        assert_eq!(Json::parse("1e3").unwrap().as_usize(), Some(1000));
+        assert_eq!(Json::parse("2.5").unwrap().as_i128(), None);
+        assert_eq!(Json::Float(f64::NAN).to_string(), "null");
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        let parsed = Json::parse(r#""a\"b\\c\/d\n\t\r\b\f\u0041\u00e9""#).unwrap();
+        assert_eq!(parsed, Json::str("a\"b\\c/d\n\t\r\u{8}\u{c}Aé"));
+        assert_eq!(Json::parse(r#""\ud83d\ude00""#).unwrap(), Json::str("😀"));
+        assert_eq!(Json::parse(r#""\ud83dx""#).unwrap(), Json::str("\u{fffd}x"));
+        assert_eq!(Json::parse(r#""\ude00""#).unwrap(), Json::str("\u{fffd}"));
+        assert_eq!(Json::parse(r#""\ud83d\u0041""#).unwrap(), Json::str("\u{fffd}A"));
+        let text = "quote \" backslash \\ newline \n tab \t bell \u{7} é 😀";
+        assert_eq!(Json::str(text).to_string(), "\"quote \\\" backslash \\\\ newline \\n tab \\t bell \\u0007 é 😀\"");
+        assert_eq!(Json::parse(&Json::str(text).to_string()).unwrap(), Json::str(text));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let error = |text: &str| Json::parse(text).unwrap_err();
+        assert_eq!(error(""), "Unexpected character at 0");
+        assert_eq!(error("[1,]"), "Unexpected character at 3");
+        assert_eq!(error("[1 2]"), "Expected `,` or `]` at 3");
+        assert_eq!(error("{\"a\" 1}"), "Expected `:` at 5");
+        assert_eq!(error("{\"a\": 1,}"), "Expected string at 8");
+        assert_eq!(error("{1: 2}"), "Expected string at 1");
+        assert_eq!(error("tru"), "Unexpected character at 3");
+        assert_eq!(error("\"abc"), "Unterminated string");
+        assert_eq!(error("\"\\u12g4\""), "Malformed unicode escape at 3");
+        assert_eq!(error("\"\\u12"), "Truncated unicode escape");
+        assert_eq!(error("-"), "Malformed number at 0");
+        assert_eq!(error("1.2.3"), "Malformed number at 0");
+        assert_eq!(error("1 2"), "Trailing characters at 2");
+    }
+
+    #[test]
+    fn renders_compactly() {
+        let value = Json::object(vec![("a", Json::Array(vec![Json::Int(1), Json::str("x")])), ("b", Json::Null)]);
+        assert_eq!(value.to_string(), "{\"a\":[1,\"x\"],\"b\":null}");
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+    }
+}
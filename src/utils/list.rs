@@ -60,8 +60,8 @@ impl<T> List<T> where T: Clone {
         vector.reverse();
         vector.pop();
         let mut tail: List<T> = List::Null;
-        for i in 0..vector.len() {
-            tail = List::Mems(vector[i].clone(), Box::new(tail));
+        for item in vector.iter() {
+            tail = List::Mems(item.clone(), Box::new(tail));
         }
         List::Mems(first, Box::new(tail))
     }
@@ -1,7 +1,5 @@
-mod list;
-
-use list::List;
-use list::{Head, Tail};
+use crate::list::List;
+use crate::list::{Head, Tail};
 
 use crate::list::AsList;
 
@@ -11,6 +9,12 @@ pub struct StringView {
     size: usize
 }
 
+impl Default for StringView {
+    fn default() -> Self {
+        StringView::new()
+    }
+}
+
 impl StringView {
     pub fn new() -> StringView {
         StringView { data: List::Null, size: 0 }
@@ -0,0 +1,193 @@
+use crate::vm::{Instruction, Proc, Word};
+
+// What is known about a stack slot. Conditions are tracked as far as
+// `push 0, neq, jnz` style code needs: JNZ only jumps on 1, so a path where
+// the top is known not to be 1 never takes the jump.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Known(Word),
+    Bool,
+    Unknown
+}
+
+impl Value {
+    fn merge(self, other: Value) -> Value {
+        let boolean = |v: Value| matches!(v, Value::Known(0) | Value::Known(1) | Value::Bool);
+        match (self, other) {
+            (x, y) if x == y => x,
+            (x, y) if boolean(x) && boolean(y) => Value::Bool,
+            _ => Value::Unknown
+        }
+    }
+}
+
+fn arithmetic(inst: Instruction, x: Value, y: Value) -> Value {
+    let (x, y) = match (x, y) {
+        (Value::Known(x), Value::Known(y)) => (x, y),
+        _ => return match inst {
+            Instruction::EQ | Instruction::NEQ => Value::Bool,
+            _ => Value::Unknown
+        }
+    };
+    match inst {
+        Instruction::ADD => Value::Known(x.wrapping_add(y)),
+        Instruction::SUB => Value::Known(x.wrapping_sub(y)),
+        Instruction::MUL => Value::Known(x.wrapping_mul(y)),
+        Instruction::DIV if x != 0 && y != 0 => Value::Known(x / y),
+        Instruction::EQ => Value::Known((x == y) as Word),
+        Instruction::NEQ => Value::Known((x != y) as Word),
+        _ => Value::Unknown
+    }
+}
+
+// How many values `inst` needs on the stack.
+fn needs(inst: Instruction) -> usize {
+    match inst {
+        Instruction::PUSH(_) | Instruction::DUMP | Instruction::JMP(_) | Instruction::BLIND => 0,
+        Instruction::PRINT | Instruction::JNZ(_) | Instruction::HALT | Instruction::LOADB | Instruction::LOADW => 1,
+        Instruction::ADD | Instruction::SUB | Instruction::MUL | Instruction::DIV | Instruction::EQ | Instruction::NEQ => 2,
+        Instruction::DUP(n) => (n as usize).saturating_add(1)
+    }
+}
+
+// The states `inst` at `pc` can continue with, and where.
+fn successors(pc: usize, inst: Instruction, mut stack: Vec<Value>) -> Vec<(usize, Vec<Value>)> {
+    match inst {
+        Instruction::PUSH(n) => {
+            stack.push(Value::Known(n));
+            vec![(pc + 1, stack)]
+        }
+        Instruction::ADD | Instruction::SUB | Instruction::MUL | Instruction::DIV | Instruction::EQ | Instruction::NEQ => {
+            let y = stack.pop().unwrap_or(Value::Unknown);
+            let x = stack.pop().unwrap_or(Value::Unknown);
+            stack.push(arithmetic(inst, x, y));
+            vec![(pc + 1, stack)]
+        }
+        Instruction::DUP(n) => {
+            stack.push(stack[stack.len() - 1 - n as usize]);
+            vec![(pc + 1, stack)]
+        }
+        Instruction::LOADB | Instruction::LOADW => {
+            stack.pop();
+            stack.push(Value::Unknown);
+            vec![(pc + 1, stack)]
+        }
+        Instruction::DUMP | Instruction::PRINT | Instruction::BLIND => vec![(pc + 1, stack)],
+        Instruction::JMP(to) => vec![(to as usize, stack)],
+        Instruction::JNZ(to) => {
+            let top = stack[stack.len() - 1];
+            let mut taken = stack.clone();
+            taken.pop();
+            let mut fall = stack;
+            if let (Value::Bool, Some(value)) = (top, fall.last_mut()) {
+                *value = Value::Known(0);
+            }
+            match top {
+                Value::Known(1) => vec![(to as usize, taken)],
+                Value::Known(_) => vec![(pc + 1, fall)],
+                _ => vec![(to as usize, taken), (pc + 1, fall)]
+            }
+        }
+        Instruction::HALT => vec![]
+    }
+}
+
+// Follows every path through the body of `proc`, starting with its inputs
+// on the stack, and checks that no instruction reaches below them, that
+// paths meet with the same stack depth and that every path leaving the body
+// (by running past its end or jumping out of it) leaves `outputs` values.
+// HALT ends a path without a check. Errors come with the index of the
+// offending instruction.
+pub fn check_proc(program: &[Instruction], proc: &Proc) -> Result<(), (usize, String)> {
+    let body = proc.start..proc.end.min(program.len());
+    let leaves = |pc: usize, depth: usize| match depth == proc.outputs {
+        true => Ok(()),
+        false => Err((pc, format!("`{}` leaves {} value{} but its stack effect {} says {}", proc.name, depth, if depth == 1 { "" } else { "s" }, proc.effect, proc.outputs)))
+    };
+    if body.is_empty() {
+        return leaves(proc.start, proc.inputs);
+    }
+    let mut states: Vec<Option<Vec<Value>>> = vec![None; body.len()];
+    states[0] = Some(vec![Value::Unknown; proc.inputs]);
+    let mut work = vec![proc.start];
+    while let Some(pc) = work.pop() {
+        let stack = states[pc - proc.start].clone().unwrap_or_default();
+        let inst = program[pc];
+        if needs(inst) > stack.len() {
+            return Err((pc, format!("`{}` needs {} value{} but `{}` only has {} on the stack here", inst.mnemonic(), needs(inst), if needs(inst) == 1 { "" } else { "s" }, proc.name, stack.len())));
+        }
+        for (next, stack) in successors(pc, inst, stack) {
+            if !body.contains(&next) {
+                leaves(pc, stack.len())?;
+                continue;
+            }
+            let state = &mut states[next - proc.start];
+            let merged = match state {
+                None => stack,
+                Some(old) if old.len() != stack.len() => {
+                    return Err((pc, format!("Paths into instruction {} of `{}` have different stack depths, {} and {}", next, proc.name, old.len(), stack.len())));
+                }
+                Some(old) => old.iter().zip(&stack).map(|(x, y)| x.merge(*y)).collect()
+            };
+            if state.as_ref() != Some(&merged) {
+                *state = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+
+    fn error(source: &str) -> (String, usize) {
+        let e = assemble(source).unwrap_err();
+        (e.message, e.line)
+    }
+
+    #[test]
+    fn accepts_balanced_procedures() {
+        assert!(assemble(".proc sq ( n -- n2 )\n    dup 0\n    mul\n.endp\n").is_ok());
+        assert!(assemble(".proc nop ( -- )\n.endp\n").is_ok());
+        // Both branches leave one value.
+        assert!(assemble(".proc pick ( c -- x )\n    .if\n        push 1\n    .else\n        push 2\n    .endif\n.endp\n").is_ok());
+        // A `.loop` takes its count off the stack when it is done.
+        assert!(assemble(".proc count ( -- )\n    .loop 3\n        print\n    .endloop\n.endp\n").is_ok());
+        // The jump is always taken, so `print` is never reached.
+        assert!(assemble(".proc f ( -- )\n    push 1\n    jnz out\n    print\n.endp\nout:\n").is_ok());
+        // A halting path needs no outputs.
+        assert!(assemble(".proc stop ( a -- b c )\n    halt\n.endp\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_underflow() {
+        assert_eq!(error(".proc f ( -- x )\n    push 1\n    add\n.endp\n"), ("`add` needs 2 values but `f` only has 1 on the stack here".to_string(), 3));
+        assert_eq!(error(".proc f ( -- )\n    print\n.endp\n"), ("`print` needs 1 value but `f` only has 0 on the stack here".to_string(), 2));
+    }
+
+    #[test]
+    fn rejects_wrong_output_counts() {
+        assert_eq!(error(".proc f ( a b -- c )\n    push 1\n.endp\n"), ("`f` leaves 3 values but its stack effect ( a b -- c ) says 1".to_string(), 2));
+        // An empty body is reported at the `.proc` line.
+        assert_eq!(error("push 0\n.proc f ( a -- )\n.endp\n"), ("`f` leaves 1 value but its stack effect ( a -- ) says 0".to_string(), 2));
+        // So is a jump out of the body.
+        assert_eq!(error(".proc f ( -- x )\n    jmp out\n.endp\nout:\n"), ("`f` leaves 0 values but its stack effect ( -- x ) says 1".to_string(), 2));
+    }
+
+    #[test]
+    fn rejects_paths_with_different_depths() {
+        let (message, line) = error(".proc f ( c -- x )\n    .if\n        push 1\n        push 2\n    .else\n        push 3\n    .endif\n    print\n.endp\n");
+        assert!(message.starts_with("Paths into instruction "), "{}", message);
+        assert!(message.ends_with(" of `f` have different stack depths, 2 and 1") || message.ends_with(" of `f` have different stack depths, 1 and 2"), "{}", message);
+        assert!(line > 1);
+    }
+
+    #[test]
+    fn rejects_malformed_procedures() {
+        assert_eq!(error(".proc f ( a -- )\nprint\n").0, "`.proc` `f` is missing its `.endp`");
+        assert_eq!(error(".proc f a -- b\n.endp\n").0, "Expected a stack effect such as `( a b -- c )` after `.proc f`");
+        assert_eq!(error(".proc f ( -- )\n.proc g ( -- )\n.endp\n.endp\n").0, "`.proc` inside `f`, which was opened on line 1");
+    }
+}
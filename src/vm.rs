@@ -1,8 +1,12 @@
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::prelude::*;
 use std::process::exit;
 use crate::vm::InstSuccess::OK;
 use crate::vm::ExitCode::{FEXT, MEXT};
+use crate::observer::Observer;
+use crate::json::Json;
+use crate::asm::{assemble_with, Assembly};
 use lazy_static::lazy_static;
 use regex::Regex;
 
@@ -12,24 +16,194 @@ use regex::Regex;
 
 pub type Word = u64;
 const STACK_CAP: usize = 2048;
-enum ExitCode {
+const SNAPSHOT_MAGIC: &str = "LVMSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
+const DATA_SECTION: &str = ".data";
+const PROCS_SECTION: &str = ".procs";
+const DEBUG_SECTION: &str = ".debug";
+#[allow(clippy::upper_case_acronyms)]
+pub enum ExitCode {
     FEXT = 101,
     MEXT = 202
 }
 
 
-#[derive(Debug)]
 pub struct VM {
     stack: [Word; STACK_CAP],
     stack_size: usize,
     program: Vec<Instruction>,
-    ptr: usize
+    ptr: usize,
+    exit_code: Option<Word>,
+    verbose: bool,
+    observers: Vec<Box<dyn Observer>>,
+    output: Box<dyn Write>,
+    history: Option<History>,
+    debug: Option<DebugInfo>,
+    // Read only bytes addressed by LOADB and LOADW.
+    data: Vec<u8>,
+    procs: Vec<Proc>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLoc {
+    pub file: String,
+    pub line: usize,
+    pub column: usize
+}
+
+impl std::fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// Maps instruction indices back to source. Stored after the code in a
+// bytecode file as a `.debug` section with one entry per line:
+//     loc <index> <line> <column> <file>
+//     label <index> <name>
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    pub locations: Vec<SourceLoc>,
+    pub labels: Vec<(String, usize)>
+}
+
+impl DebugInfo {
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|(l, _i)| l == name).map(|(_l, i)| *i)
+    }
+    pub fn labels_at(&self, index: usize) -> Vec<&str> {
+        self.labels.iter().filter(|(_l, i)| *i == index).map(|(l, _i)| l.as_str()).collect()
+    }
+    fn encode(&self) -> String {
+        let mut ret = String::from(DEBUG_SECTION);
+        ret += "\n";
+        for (i, loc) in self.locations.iter().enumerate() {
+            ret += format!("loc {} {} {} {}\n", i, loc.line, loc.column, loc.file).as_str();
+        }
+        for (name, i) in &self.labels {
+            ret += format!("label {} {}\n", i, name).as_str();
+        }
+        ret
+    }
+    fn decode(section: &str) -> std::io::Result<DebugInfo> {
+        let invalid = |line: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid debug entry `{}`", line));
+        let mut ret = DebugInfo::default();
+        for line in section.lines().filter(|l| !l.trim().is_empty()) {
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            match fields[..] {
+                ["loc", index, l, c, file] => {
+                    if index.parse::<usize>().ok() != Some(ret.locations.len()) {
+                        return Err(invalid(line));
+                    }
+                    let (l, c) = match (l.parse::<usize>(), c.parse::<usize>()) {
+                        (Ok(l), Ok(c)) => (l, c),
+                        _ => return Err(invalid(line))
+                    };
+                    ret.locations.push(SourceLoc { file: file.to_string(), line: l, column: c });
+                }
+                ["label", index, name] => match index.parse::<usize>() {
+                    Ok(index) => ret.labels.push((name.to_string(), index)),
+                    Err(_e) => return Err(invalid(line))
+                },
+                _ => return Err(invalid(line))
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// A `.proc name (in -- out)` block of the source: instructions `start` up to
+// but not including `end`, which take `inputs` values off the stack and leave
+// `outputs` in their place. Stored after the code and data in a bytecode file
+// as a `.procs` section with one entry per line:
+//     proc <start> <end> <name> <effect>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proc {
+    pub name: String,
+    pub effect: String,
+    pub inputs: usize,
+    pub outputs: usize,
+    pub start: usize,
+    pub end: usize
+}
+
+impl Proc {
+    // The number of inputs and outputs of a stack effect such as `( a b -- c )`.
+    pub fn parse_effect(effect: &str) -> Option<(usize, usize)> {
+        let inner = effect.trim().strip_prefix('(')?.strip_suffix(')')?;
+        let (inputs, outputs) = inner.split_once("--")?;
+        if outputs.contains("--") {
+            return None;
+        }
+        Some((inputs.split_whitespace().count(), outputs.split_whitespace().count()))
+    }
+    fn encode(procs: &[Proc]) -> String {
+        let mut ret = format!("{}\n", PROCS_SECTION);
+        for p in procs {
+            ret += format!("proc {} {} {} {}\n", p.start, p.end, p.name, p.effect).as_str();
+        }
+        ret
+    }
+    fn decode(section: &str) -> std::io::Result<Vec<Proc>> {
+        let invalid = |line: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid procedure entry `{}`", line));
+        let mut ret = vec![];
+        for line in section.lines().filter(|l| !l.trim().is_empty()) {
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            let proc = match fields[..] {
+                ["proc", start, end, name, effect] => match (start.parse::<usize>(), end.parse::<usize>(), Proc::parse_effect(effect)) {
+                    (Ok(start), Ok(end), Some((inputs, outputs))) => Proc { name: name.to_string(), effect: effect.to_string(), inputs, outputs, start, end },
+                    _ => return Err(invalid(line))
+                },
+                _ => return Err(invalid(line))
+            };
+            ret.push(proc);
+        }
+        Ok(ret)
+    }
+}
+
+// Splits `text` at the header line of an optional section.
+pub(crate) fn split_section<'a>(text: &'a str, name: &str) -> (&'a str, Option<&'a str>) {
+    match text.split_once(format!("\n{}\n", name).as_str()) {
+        Some((before, section)) => (before, Some(section)),
+        None => (text, None)
+    }
+}
+
+// Enough to undo one executed instruction. Popped values are still in their
+// slots above `stack_size`, so only slots that pushes overwrote are kept.
+struct Undo {
+    ptr: usize,
+    stack_size: usize,
+    exit_code: Option<Word>,
+    writes: Vec<(usize, Word)>
+}
+
+struct History {
+    cap: usize,
+    records: VecDeque<Undo>
+}
+
+impl std::fmt::Debug for VM {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VM")
+            .field("stack", &self.stack())
+            .field("stack_size", &self.stack_size)
+            .field("program", &self.program)
+            .field("ptr", &self.ptr)
+            .field("data", &self.data.len())
+            .field("exit_code", &self.exit_code)
+            .field("verbose", &self.verbose)
+            .field("observers", &self.observers.len())
+            .field("history", &self.history_len())
+            .finish()
+    }
 }
 
 fn bin_formatter(s: String) -> String {
     let mut b = String::from("");
     let mut l = s.len();
-    while l % 8 != 0 {
+    while !l.is_multiple_of(8) {
         b += "0";
         l += 1;
     }
@@ -39,7 +213,7 @@ fn bin_formatter(s: String) -> String {
 fn bin_formatter_o(s: String) -> String {
     let mut b = String::from("");
     let mut l = s.len();
-    while l % 64 != 0 {
+    while !l.is_multiple_of(64) {
         b += "0";
         l += 1;
     }
@@ -105,12 +279,20 @@ fn inst_to_string(inst: Instruction) -> String {
             ret += bin_formatter(format!("{:b}", 13)).as_str();
             ret += bin_formatter_o(format!("{:b}", 0)).as_str();
         }
+        Instruction::LOADB => {
+            ret += bin_formatter(format!("{:b}", 14)).as_str();
+            ret += bin_formatter_o(format!("{:b}", 0)).as_str();
+        }
+        Instruction::LOADW => {
+            ret += bin_formatter(format!("{:b}", 15)).as_str();
+            ret += bin_formatter_o(format!("{:b}", 0)).as_str();
+        }
     }
     ret
 }
 
-fn make_inst(op: isize, operand: u64) -> Instruction {
-    match op {
+fn make_inst(op: isize, operand: u64) -> Option<Instruction> {
+    Some(match op {
         0 => Instruction::PUSH(operand),
         1 => Instruction::ADD,
         2 => Instruction::SUB,
@@ -125,11 +307,84 @@ fn make_inst(op: isize, operand: u64) -> Instruction {
         11 => Instruction::HALT,
         12 => Instruction::BLIND,
         13 => Instruction::NEQ,
-        _ => {
-            eprintln!("Could not make instruction with op code {} and operand {}", op, operand);
-            exit(FEXT as i32);
+        14 => Instruction::LOADB,
+        15 => Instruction::LOADW,
+        _ => return None
+    })
+}
+
+// Rejects anything but whole 72 digit instructions with known opcodes.
+fn decode_byte_code(buf: &str) -> std::io::Result<Vec<Instruction>> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let buf = buf.trim();
+    if let Some(c) = buf.chars().find(|c| *c != '0' && *c != '1') {
+        return Err(invalid(format!("Invalid character `{}` in bytecode", c)));
+    }
+    if !buf.len().is_multiple_of(72) {
+        return Err(invalid(format!("Bytecode of {} digits does not split into 72 digit instructions", buf.len())));
+    }
+    lazy_static! {
+        static ref RE: Regex = 
+        Regex::new(
+            r"(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)")
+            .unwrap();
+    }
+    let mut comp = Vec::<String>::new();
+    for cap in RE.captures_iter(buf) {
+        comp.push(String::from(&cap[0]));
+    }
+    lazy_static! {
+        static ref BYTE: Regex = Regex::new(r"(\d\d\d\d\d\d\d\d)").unwrap();
+    }
+    let mut inst_id = 0;
+    let mut operand = 0u64;
+    let mut ret = Vec::<Instruction>::new();
+    for binst in comp {
+        let mut o_comp = String::from("");
+        for (counter, byte) in BYTE.captures_iter(&binst).enumerate() {
+            let byte_i = isize::from_str_radix(&byte[0], 2).map_err(|e| invalid(e.to_string()))?;
+            if counter == 0 {
+                inst_id = byte_i;
+            } else if counter != 8 && counter != 0{
+                o_comp += &byte[0];
+            } else if counter == 8 {
+                o_comp += &byte[0];
+                operand = u64::from_str_radix(&o_comp, 2).map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+        match make_inst(inst_id, operand) {
+            Some(inst) => ret.push(inst),
+            None => return Err(invalid(format!("Unknown opcode {} in instruction {}", inst_id, ret.len())))
         }
     }
+    Ok(ret)
+}
+
+// Two hex digits per byte, 32 bytes to a line.
+fn encode_data(data: &[u8]) -> String {
+    let lines: Vec<String> = data.chunks(32).map(|c| c.iter().map(|b| format!("{:02x}", b)).collect()).collect();
+    lines.join("\n")
+}
+
+fn decode_data(text: &str) -> std::io::Result<Vec<u8>> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid data section");
+    if !digits.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).map_err(|_e| invalid()))
+        .collect()
+}
+
+// FNV-1a, only meant to catch corrupted or hand-edited files.
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 impl VM {
@@ -138,7 +393,15 @@ impl VM {
             stack: [0; STACK_CAP],
             stack_size: 0,
             program: vec![],
-            ptr: 0
+            ptr: 0,
+            exit_code: None,
+            verbose: false,
+            observers: vec![],
+            output: Box::new(std::io::stdout()),
+            history: None,
+            debug: None,
+            data: vec![],
+            procs: vec![]
         }
     }
     pub fn get_byte_code(&self) -> String {
@@ -148,163 +411,322 @@ impl VM {
         }
         ret
     }
+    // Identifies the loaded program, e.g. to check that a recording belongs to it.
+    pub fn fingerprint(&self) -> u64 {
+        match self.data.is_empty() {
+            true => checksum(self.get_byte_code().as_bytes()),
+            false => checksum(format!("{}{}", self.get_byte_code(), encode_data(&self.data)).as_bytes())
+        }
+    }
+    // The code, then a `.data` section holding the data segment in hex, a
+    // `.procs` section and the debug section, each only if there is one.
     pub fn write_to_file(&self, path: &str) -> std::io::Result<()> {
         let mut file = File::create(path)?;
-        file.write_all(self.get_byte_code().as_bytes())?;
-        Ok(())
+        file.write_all(self.encode().as_bytes())
+    }
+    // What `write_to_file` writes.
+    pub fn encode(&self) -> String {
+        let mut ret = self.get_byte_code();
+        if !self.data.is_empty() {
+            ret += format!("\n{}\n{}\n", DATA_SECTION, encode_data(&self.data)).as_str();
+        }
+        if !self.procs.is_empty() {
+            ret += "\n";
+            ret += Proc::encode(&self.procs).as_str();
+        }
+        if let Some(debug) = &self.debug {
+            ret += "\n";
+            ret += debug.encode().as_str();
+        }
+        ret
     }
     pub fn compile_source(path: &str, output: &str) -> std::io::Result<()> {
+        VM::compile_source_with(path, output, false, &[])
+    }
+    // Like `compile_source`, optionally followed by a debug section that maps
+    // every instruction back to its place in the source. `.include` files are
+    // looked up next to the including file, then in `include_dirs`.
+    pub fn compile_source_with(path: &str, output: &str, debug_info: bool, include_dirs: &[String]) -> std::io::Result<()> {
         let mut file = File::open(path)?;
         let mut buf = String::new();
         VM::unwrap(file.read_to_string(&mut buf), format!("Could not read file at path {}", path).as_str());
-        let mut counter = 0;
-        let mut opid: isize = 0;
-        let mut oprn = 0u64;
-        let mut ret = Vec::<Instruction>::new();
-        for line in buf.lines() {
-            let toks: Vec<&str> = line.split(' ').into_iter().collect();
-            if toks.len() == 0 {
-                continue;
-            }
-            match toks[0] {
-                "dump"
-                | "print"
-                | "add"
-                | "sub"
-                | "mul"
-                | "div"
-                | "eq"
-                | "neq"
-                | "halt"
-                | "blind" => { // no operands
-                    match toks[0] { // add 1, sub 2, mul 3, div 4, dump 6, print 7, eq 9, halt 11
-                        "dump" => {opid = 6}
-                        "print" => {opid = 7}
-                        "add" => {opid = 1}
-                        "sub" => {opid = 2}
-                        "mul" => {opid = 3}
-                        "div" => {opid = 4}
-                        "eq" => {opid = 9}
-                        "neq" => {opid = 13}
-                        "halt" => {opid = 11}
-                        "blind" => {opid = 12}
-                        _ => {}
-                    }
-                }
-                "push"
-                | "dup"
-                | "jmp"
-                | "jnz" => {// 0, dup 5, jmp 8, jnz 10
-                    if toks.len() == 1 {
-                        eprintln!("Invalid syntax at line {}.", counter);
-                        exit(FEXT as i32);
-                    }
-                    let operand = match toks[1].parse::<u64>() {
-                        Ok(i) => i,
-                        Err(_e) => {
-                            eprintln!("Invalid syntax at line {}.", counter);
-                            exit(FEXT as i32);
-                        }
-                    };
-                    match toks[0] {
-                        "push" => {
-                            opid = 0 as isize;
-                            oprn = operand;
-                        } 
-                        "dup" => {
-                            opid = 5 as isize;
-                            oprn = operand;
-                        } 
-                        "jmp" => {
-                            opid = 8 as isize;
-                            oprn = operand;
-                        } 
-                        "jnz" => {
-                            opid = 10 as isize;
-                            oprn = operand;
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {
-                    eprintln!("Invalid syntax at line {}.", counter);
-                    exit(FEXT as i32);
-                }
+        let assembly = match assemble_with(&buf, Some(path), include_dirs) {
+            Ok(assembly) => assembly,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                exit(FEXT as i32);
             }
-            ret.push(make_inst(opid, oprn));
-            counter += 1;
-        }
+        };
+        VM::write_assembly(assembly, path, output, debug_info)
+    }
+    // Writes an already assembled `path` to `output`.
+    pub fn write_assembly(assembly: Assembly, path: &str, output: &str, debug_info: bool) -> std::io::Result<()> {
         let mut retvm = VM::init(); 
-        retvm.program = ret;
+        if debug_info {
+            retvm.debug = Some(assembly.debug_info(path));
+        }
+        retvm.program = assembly.program;
+        retvm.data = assembly.data;
+        retvm.procs = assembly.procs;
         VM::unwrap(retvm.write_to_file(output), "Could not write to output file specified.");
         Ok(())
     }
     pub fn load_from_file(&mut self, path: &str) -> std::io::Result<()> {
-        lazy_static! {
-            static ref RE: Regex = 
-            Regex::new(
-                r"(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)(\d\d\d\d\d\d\d\d)")
-                .unwrap();
-        }
         let mut file = File::open(path)?;
         let mut buf = String::new();
         VM::unwrap(file.read_to_string(&mut buf), format!("Could not read file at path {}", path).as_str());
-        let mut comp = Vec::<String>::new();
-        for cap in RE.captures_iter(&buf) {
-            comp.push(String::from(&cap[0]));
-        }
-        lazy_static! {
-            static ref BYTE: Regex = Regex::new(r"(\d\d\d\d\d\d\d\d)").unwrap();
-        }
-        //println!("comp: {:#?}", comp);
-        let mut inst_id = 0;
-        let mut operand = 0u64;
-        let mut ret = Vec::<Instruction>::new();
-        for binst in comp {
-            let mut o_comp = String::from("");
-            let mut counter = 0;
-            for byte in BYTE.captures_iter(&binst) {
-                let byte_i = isize::from_str_radix(&byte[0], 2).unwrap();
-                if counter == 0 {
-                    inst_id = byte_i;
-                } else if counter != 8 && counter != 0{
-                    o_comp += format!("{}", &byte[0]).as_str();
-                } else if counter == 8 {
-                    o_comp += format!("{}", &byte[0]).as_str();
-                    operand = isize::from_str_radix(&o_comp, 2).unwrap() as u64;
+        self.decode(&buf)
+    }
+    // Loads what `encode` returns.
+    pub fn decode(&mut self, buf: &str) -> std::io::Result<()> {
+        let (code, debug) = match split_section(buf, DEBUG_SECTION) {
+            (code, Some(debug)) => (code, Some(DebugInfo::decode(debug)?)),
+            (code, None) => (code, None)
+        };
+        let (code, procs) = match split_section(code, PROCS_SECTION) {
+            (code, Some(procs)) => (code, Proc::decode(procs)?),
+            (code, None) => (code, vec![])
+        };
+        let (code, data) = match split_section(code, DATA_SECTION) {
+            (code, Some(data)) => (code, decode_data(data)?),
+            (code, None) => (code, vec![])
+        };
+        self.load_program(decode_byte_code(code)?);
+        self.data = data;
+        self.procs = procs;
+        if debug.is_some() {
+            self.debug = debug;
+        }
+        Ok(())
+    }
+    pub fn snapshot(&self) -> String {
+        let mut body = format!("{} {}\n", SNAPSHOT_MAGIC, SNAPSHOT_VERSION);
+        body += format!("stack_size {}\n", self.stack_size).as_str();
+        body += format!("ptr {}\n", self.ptr).as_str();
+        body += match self.exit_code {
+            Some(code) => format!("exit_code {}\n", code),
+            None => "exit_code\n".to_string()
+        }.as_str();
+        body += "stack";
+        for n in 0..self.stack_size {
+            body += format!(" {}", self.stack[n]).as_str();
+        }
+        body += "\n";
+        body += format!("program {}\n", self.get_byte_code()).as_str();
+        body += format!("data {}\n", encode_data(&self.data).replace('\n', "")).as_str();
+        // Kept so that a resumed run still reports source locations.
+        if !self.procs.is_empty() {
+            body += Proc::encode(&self.procs).as_str();
+        }
+        if let Some(debug) = &self.debug {
+            body += debug.encode().as_str();
+        }
+        format!("{}checksum {:016x}\n", body, checksum(body.as_bytes()))
+    }
+    // Written next to `path` and renamed over it, so that an interrupted
+    // checkpoint leaves the previous one intact.
+    pub fn write_snapshot(&self, path: &str) -> std::io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.snapshot().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+    pub fn restore(snapshot: &str) -> std::io::Result<VM> {
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid snapshot: {}", msg));
+        let split = match snapshot.rfind("checksum ") {
+            Some(i) => i,
+            None => return Err(invalid("missing checksum"))
+        };
+        let (body, sum) = snapshot.split_at(split);
+        let sum = u64::from_str_radix(sum["checksum ".len()..].trim(), 16).map_err(|_e| invalid("malformed checksum"))?;
+        if sum != checksum(body.as_bytes()) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let (body, debug) = match split_section(body, DEBUG_SECTION) {
+            (body, Some(debug)) => (body, Some(DebugInfo::decode(debug)?)),
+            (body, None) => (body, None)
+        };
+        let (body, procs) = match split_section(body, PROCS_SECTION) {
+            (body, Some(procs)) => (body, Proc::decode(procs)?),
+            (body, None) => (body, vec![])
+        };
+        let mut lines = body.lines();
+        match lines.next().map(|l| l.split(' ').collect::<Vec<&str>>()) {
+            Some(header) if header.len() == 2 && header[0] == SNAPSHOT_MAGIC => {
+                if header[1].parse::<u32>() != Ok(SNAPSHOT_VERSION) {
+                    return Err(invalid(format!("unsupported version {}", header[1]).as_str()));
                 }
-                counter += 1;
             }
-            ret.push(make_inst(inst_id, operand));     
+            _ => return Err(invalid("missing header"))
         }
-        self.load_program(ret);
-        Ok(())
+        let mut field = |name: &str| -> std::io::Result<String> {
+            match lines.next() {
+                Some(line) if line == name => Ok(String::new()),
+                Some(line) if line.starts_with(format!("{} ", name).as_str()) => Ok(line[name.len() + 1..].to_string()),
+                _ => Err(invalid(format!("missing field {}", name).as_str()))
+            }
+        };
+        let stack_size = field("stack_size")?.parse::<usize>().map_err(|_e| invalid("malformed stack_size"))?;
+        let ptr = field("ptr")?.parse::<usize>().map_err(|_e| invalid("malformed ptr"))?;
+        let exit_code = match field("exit_code")?.as_str() {
+            "" => None,
+            code => Some(code.parse::<Word>().map_err(|_e| invalid("malformed exit_code"))?)
+        };
+        let stack = field("stack")?;
+        let program = field("program")?;
+        let data = decode_data(&field("data")?)?;
+        if let Some(line) = lines.find(|l| !l.trim().is_empty()) {
+            return Err(invalid(format!("unexpected line `{}`", line).as_str()));
+        }
+
+        let mut vm = VM::init();
+        for word in stack.split(' ').filter(|w| !w.is_empty()) {
+            if vm.stack_size == STACK_CAP {
+                return Err(invalid("stack exceeds capacity"));
+            }
+            vm.stack[vm.stack_size] = word.parse::<Word>().map_err(|_e| invalid("malformed stack word"))?;
+            vm.stack_size += 1;
+        }
+        if vm.stack_size != stack_size {
+            return Err(invalid("stack_size does not match stack contents"));
+        }
+        vm.load_program(decode_byte_code(&program).map_err(|e| invalid(&e.to_string()))?);
+        if ptr > vm.program.len() {
+            return Err(invalid("ptr out of program bounds"));
+        }
+        vm.ptr = ptr;
+        vm.exit_code = exit_code;
+        vm.data = data;
+        vm.procs = procs;
+        vm.debug = debug;
+        Ok(vm)
+    }
+    pub fn load_snapshot(path: &str) -> std::io::Result<VM> {
+        let mut file = File::open(path)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        VM::restore(&buf)
     }
     fn push(&mut self, o: Word) -> Result<InstSuccess, InstError> {
-        let mut ret = Result::Ok(OK);
+        if self.stack_size + 1 == STACK_CAP {
+            return Err(InstError::StackOverflow { capacity: STACK_CAP - 1 });
+        }
         self.stack_size += 1;
-        if self.stack_size == STACK_CAP {
-            ret = Err(InstError::StackOverflow(String::from("Stack overflow occurred.\n")));
-            return ret;
+        if let Some(undo) = self.history.as_mut().and_then(|h| h.records.back_mut()) {
+            undo.writes.push((self.stack_size - 1, self.stack[self.stack_size - 1]));
         }
         self.stack[self.stack_size - 1] = o;
-        ret
+        if !self.observers.is_empty() {
+            self.notify(|obs, stack| obs.on_push(o, stack));
+        }
+        Ok(OK)
     }
     fn pop(&mut self) -> Result<Word, InstError> {
         if self.stack_size == 0 {
-            Err(InstError::StackUnderflow("Can not pop from stack with 0 elements.\n".to_string()))
+            Err(InstError::StackUnderflow { needed: 1, found: 0 })
         } else {
             self.stack_size -= 1;
-            Ok(self.stack[self.stack_size])
+            let o = self.stack[self.stack_size];
+            if !self.observers.is_empty() {
+                self.notify(|obs, stack| obs.on_pop(o, stack));
+            }
+            Ok(o)
+        }
+    }
+    fn jump(&mut self, to: usize) {
+        let from = self.ptr;
+        self.ptr = to;
+        if !self.observers.is_empty() {
+            self.notify(|obs, _stack| obs.on_jump(from, to));
+        }
+    }
+    fn notify<F: FnMut(&mut dyn Observer, &[Word])>(&mut self, mut f: F) {
+        let stack = &self.stack[..self.stack_size];
+        for o in self.observers.iter_mut() {
+            f(o.as_mut(), stack);
+        }
+    }
+    // Keeps undo information for the last `cap` executed instructions so they
+    // can be stepped back over; a cap of 0 turns recording off.
+    pub fn record_history(&mut self, cap: usize) {
+        self.history = if cap == 0 {
+            None
+        } else {
+            let mut records = self.history.take().map(|h| h.records).unwrap_or_default();
+            while records.len() > cap {
+                records.pop_front();
+            }
+            Some(History { cap, records })
+        };
+    }
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map(|h| h.records.len()).unwrap_or(0)
+    }
+    // Undoes the most recently executed instruction. Output it produced
+    // stays printed. Returns false when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|h| h.records.pop_back()) {
+            Some(undo) => undo,
+            None => return false
+        };
+        for (slot, old) in undo.writes.into_iter().rev() {
+            self.stack[slot] = old;
         }
+        self.stack_size = undo.stack_size;
+        self.ptr = undo.ptr;
+        self.exit_code = undo.exit_code;
+        true
+    }
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+    pub fn clear_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        std::mem::take(&mut self.observers)
     }
     fn last(&self) -> Result<Word, InstError> {
         if self.stack_size == 0 {
-            Err(InstError::StackUnderflow("Can not get last from stack with 0 elements.\n".to_string()))
+            Err(InstError::StackUnderflow { needed: 1, found: 0 })
         } else {
             Ok(self.stack[self.stack_size - 1])
         }
     }
+    fn need(&self, n: usize) -> Result<(), InstError> {
+        if self.stack_size < n {
+            return Err(InstError::StackUnderflow { needed: n, found: self.stack_size });
+        }
+        Ok(())
+    }
+    // Pops the two operands of a binary instruction, returned in push order.
+    fn pop2(&mut self) -> Result<(Word, Word), InstError> {
+        self.need(2)?;
+        let y = self.pop()?;
+        let x = self.pop()?;
+        Ok((x, y))
+    }
+    // Pops an address and reads `width` bytes of data there, little endian.
+    // The address stays on the stack if it is out of bounds.
+    fn load(&mut self, width: usize) -> Result<Word, InstError> {
+        let address = self.last()?;
+        let range = (address as usize).checked_add(width).filter(|end| address < self.data.len() as Word && *end <= self.data.len());
+        let end = match range {
+            Some(end) => end,
+            None => return Err(InstError::DataOutOfBounds { address, width, data_len: self.data.len() })
+        };
+        self.pop()?;
+        let mut bytes = [0u8; 8];
+        bytes[..width].copy_from_slice(&self.data[address as usize..end]);
+        Ok(Word::from_le_bytes(bytes))
+    }
+    // Jumping just past the last instruction ends the program like running
+    // off its end does, so a label at the very end can be jumped to.
+    fn check_target(&self, to: Word) -> Result<(), InstError> {
+        if to > self.program.len() as Word {
+            return Err(InstError::IllegalMemAccess { target: to, program_len: self.program.len() });
+        }
+        Ok(())
+    }
     fn unwrap<T, U> (r: std::result::Result<T, U>, on_err: &str) -> T 
         where U: core::fmt::Debug 
     {
@@ -322,7 +744,46 @@ impl VM {
             self.program.push(i);
         }
     }
-    pub fn execute_instruction(&mut self,i: Instruction) -> Result<InstSuccess, InstError> {
+    // Drops every instruction from `len` on; a pc past the new end is moved
+    // back onto it.
+    pub fn truncate_program(&mut self, len: usize) {
+        self.program.truncate(len);
+        self.ptr = self.ptr.min(self.program.len());
+    }
+    pub fn execute_instruction(&mut self, i: Instruction) -> Result<InstSuccess, InstError> {
+        if let Some(history) = self.history.as_mut() {
+            history.records.push_back(Undo { ptr: self.ptr, stack_size: self.stack_size, exit_code: self.exit_code, writes: vec![] });
+        }
+        let ret = self.observed(i);
+        // Only steps that happened can be undone; a failed one must not push
+        // a real record out of the history.
+        if let Some(history) = self.history.as_mut() {
+            match ret {
+                Ok(_) if history.records.len() > history.cap => {
+                    history.records.pop_front();
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    history.records.pop_back();
+                }
+            }
+        }
+        ret
+    }
+    fn observed(&mut self, i: Instruction) -> Result<InstSuccess, InstError> {
+        if self.observers.is_empty() {
+            return self.execute(i);
+        }
+        let pc = self.ptr;
+        self.notify(|o, stack| o.before_instruction(pc, i, stack));
+        let ret = self.execute(i);
+        match &ret {
+            Ok(_) => self.notify(|o, stack| o.after_instruction(pc, i, stack)),
+            Err(e) => self.notify(|o, stack| o.on_error(pc, i, e, stack))
+        }
+        ret
+    }
+    fn execute(&mut self, i: Instruction) -> Result<InstSuccess, InstError> {
         let mut ret = Result::Ok(OK);
         match i {
             Instruction::PUSH(operand) => {
@@ -330,116 +791,228 @@ impl VM {
                 self.ptr += 1; 
             },
 
-            Instruction::ADD => 
-                if self.stack_size < 2 { return Err(InstError::StackUnderflow("Too little elements on the stack for ADD.\n".to_string())); } 
-                else { 
-                    self.stack[self.stack_size - 1] += VM::unwrap(self.pop(), "Could not pop from stack."); 
-                    self.ptr += 1;  
-                }
-            
-            Instruction::SUB => 
-                if self.stack_size < 2 { return Err(InstError::StackUnderflow("Too little elements on the stack for ADD.".to_string())); } 
-                else { self.stack[self.stack_size - 1] -= VM::unwrap(self.pop(), "Could not pop from stack."); self.ptr += 1; }
-            
-            Instruction::MUL => 
-                if self.stack_size < 2 { return Err(InstError::StackUnderflow("Too little elements on the stack for ADD.".to_string())); } 
-                else { self.stack[self.stack_size - 1] *= VM::unwrap(self.pop(), "Could not pop from stack."); self.ptr += 1; }
-            
-            Instruction::DIV => 
-                if self.stack_size < 2 { return Err(InstError::StackUnderflow("Too little elements on the stack for ADD.".to_string())); } 
-                else { 
-                    let y = VM::unwrap(self.pop(), "Could not pop from stack");
-                    if self.stack[self.stack_size - 1] == 0 || y == 0 {
-                        return Err(InstError::DivByZero("Can not divide by zero.".to_string()));
-                    }
-                    self.stack[self.stack_size - 1] /= y;
-                    self.ptr += 1; 
+            Instruction::ADD => {
+                let (x, y) = self.pop2()?;
+                ret = self.push(x.wrapping_add(y));
+                self.ptr += 1;
+            }
+            Instruction::SUB => {
+                let (x, y) = self.pop2()?;
+                ret = self.push(x.wrapping_sub(y));
+                self.ptr += 1;
+            }
+            Instruction::MUL => {
+                let (x, y) = self.pop2()?;
+                ret = self.push(x.wrapping_mul(y));
+                self.ptr += 1;
+            }
+            Instruction::DIV => {
+                self.need(2)?;
+                if self.stack[self.stack_size - 2] == 0 || self.stack[self.stack_size - 1] == 0 {
+                    return Err(InstError::DivByZero);
                 }
+                let (x, y) = self.pop2()?;
+                ret = self.push(x / y);
+                self.ptr += 1;
+            }
 
             Instruction::DUMP => {
-                println!("> STACK: ");
+                let mut text = String::from("> STACK: \n");
                 for n in 0..self.stack_size {
-                    println!("\t| {} : {}", n, self.stack[n]);
+                    text += format!("\t| {} : {}\n", n, self.stack[n]).as_str();
                 }
                 if self.stack_size == 0 {
-                    println!("\t | EMPTY |");
+                    text += "\t | EMPTY |\n";
                 }
+                VM::unwrap(self.output.write_all(text.as_bytes()), "Could not write program output.");
                 self.ptr += 1;  
             }
 
             Instruction::JMP(operand) => {
-                if operand as usize >= self.program.len() {
-                    return Err(InstError::IllegalMemAccess(format!("Can not jump to line {}; can not access memory there.", operand)))
-                }
-                self.ptr = operand as usize;
+                self.check_target(operand)?;
+                self.jump(operand as usize);
             }
             Instruction::JNZ(operand) => {
-                if operand as usize >= self.program.len() {
-                    return Err(InstError::IllegalMemAccess(format!("Can not jump to line {}; can not access memory there.", operand)))
-                } else if self.stack_size == 0 {
-                    return Err(InstError::StackUnderflow("Can not JNZ with zero elements on stack.".to_string()));
-                }
-                if VM::unwrap(self.last(), "Could not get last member of stack in inst JNZ.") == 1 {
-                    self.stack_size -= 1;
-                    self.ptr = operand as usize;
+                self.check_target(operand)?;
+                if self.last()? == 1 {
+                    self.pop()?;
+                    self.jump(operand as usize);
                 } else {
                     self.ptr += 1;
                 }
             }
             Instruction::PRINT => {
-                println!("{}", VM::unwrap(self.last(), "Unknwon error eccurred, could not print."));
+                let top = self.last()?;
+                VM::unwrap(writeln!(self.output, "{}", top), "Could not write program output.");
                 self.ptr += 1;
             }
             Instruction::HALT => {
-                let ecode = VM::unwrap(self.pop(), "Could not pop from stack.") as i32;
-                println!("\n\t> Program exited with code {}.", ecode);
-                exit(ecode);
+                let ecode = self.pop()?;
+                if self.verbose {
+                    VM::unwrap(writeln!(self.output, "\n\t> Program exited with code {}.", ecode), "Could not write program output.");
+                }
+                self.exit_code = Some(ecode);
             }
             Instruction::EQ => {
-                self.stack[self.stack_size - 2] =
-                    (self.stack[self.stack_size - 2] == self.stack[self.stack_size - 1]) as Word;
-                
-                self.stack_size -= 1;
+                let (x, y) = self.pop2()?;
+                ret = self.push((x == y) as Word);
                 self.ptr += 1;
             }
             Instruction::NEQ => {
-                self.stack[self.stack_size - 2] =
-                    (self.stack[self.stack_size - 2] != self.stack[self.stack_size - 1]) as Word;
-                
-                self.stack_size -= 1;
+                let (x, y) = self.pop2()?;
+                ret = self.push((x != y) as Word);
                 self.ptr += 1;
             }
             Instruction::DUP(operand) => {
                 let operand = operand as usize;
-                if self.stack_size - operand <= 0 {
-                    return Err(InstError::StackUnderflow(format!("Could not fetch value at {} from stack.", self.stack_size - operand)))
+                if operand >= self.stack_size {
+                    return Err(InstError::StackUnderflow { needed: operand + 1, found: self.stack_size });
                 }
-                VM::unwrap(self.push(self.stack[self.stack_size - 1 - operand]), "Could not push duped value to stack.");
+                ret = self.push(self.stack[self.stack_size - 1 - operand]);
                 self.ptr += 1;
             }
             Instruction::BLIND => {
                 self.ptr += 1;
             }
+            Instruction::LOADB => {
+                let value = self.load(1)?;
+                ret = self.push(value);
+                self.ptr += 1;
+            }
+            Instruction::LOADW => {
+                let value = self.load(8)?;
+                ret = self.push(value);
+                self.ptr += 1;
+            }
         };
         ret
     }
-    pub fn run_program(&mut self) {
-        let mut i = 0;
-        while i < self.program.len() {
-            match self.execute_instruction(self.program[i]) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("{:?}", e);
-                    exit(MEXT as i32);
+    pub fn run_program(&mut self) -> RunOutcome {
+        self.run_checkpointed(None)
+    }
+    // Writes a snapshot to `path` every `every` executed instructions, so an
+    // interrupted run can be picked up again with `VM::load_snapshot`.
+    pub fn run_checkpointed(&mut self, checkpoint: Option<(&str, usize)>) -> RunOutcome {
+        let mut steps = 0usize;
+        loop {
+            if let Some((path, every)) = checkpoint {
+                if steps > 0 && steps.is_multiple_of(every) {
+                    VM::unwrap(self.write_snapshot(path), "Could not write checkpoint.");
                 }
-            };
-            i = self.ptr;
+            }
+            steps += 1;
+            match self.step() {
+                StepStatus::Running => {}
+                StepStatus::Halted => return self.outcome(),
+                StepStatus::Error(error) => return RunOutcome::Error(error)
+            }
+        }
+    }
+    // Captures the VM state at the failing instruction; `ptr` still points
+    // at it since instructions only advance on success.
+    fn runtime_error(&self, kind: InstError) -> RuntimeError {
+        RuntimeError {
+            pc: self.ptr,
+            instruction: self.program[self.ptr],
+            stack: self.stack().to_vec(),
+            location: self.location(self.ptr).cloned(),
+            kind
+        }
+    }
+    fn outcome(&self) -> RunOutcome {
+        match self.exit_code {
+            Some(code) => RunOutcome::Halted(code),
+            None => RunOutcome::Finished
+        }
+    }
+    // Executes the instruction at `ptr`. Stepping a VM that has run off the
+    // end of its program is a no-op that reports `Halted`.
+    pub fn step(&mut self) -> StepStatus {
+        if self.halted() {
+            return StepStatus::Halted;
+        }
+        match self.execute_instruction(self.program[self.ptr]) {
+            Ok(_) if self.halted() => StepStatus::Halted,
+            Ok(_) => StepStatus::Running,
+            Err(kind) => StepStatus::Error(self.runtime_error(kind))
+        }
+    }
+    // Steps at least once, then keeps going until `ptr` reaches `pc` or the
+    // program stops.
+    pub fn run_until(&mut self, pc: usize) -> StepStatus {
+        loop {
+            let status = self.step();
+            if status != StepStatus::Running || self.ptr == pc {
+                return status;
+            }
+        }
+    }
+    pub fn run_for(&mut self, n: usize) -> StepStatus {
+        let mut status = if self.halted() { StepStatus::Halted } else { StepStatus::Running };
+        for _ in 0..n {
+            status = self.step();
+            if status != StepStatus::Running {
+                break;
+            }
         }
+        status
+    }
+    fn halted(&self) -> bool {
+        self.exit_code.is_some() || self.ptr >= self.program.len()
+    }
+    // Where DUMP, PRINT and the verbose exit banner write to; stdout by default.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+    pub fn exit_code(&self) -> Option<Word> {
+        self.exit_code
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.data = data;
+    }
+    pub fn procs(&self) -> &[Proc] {
+        &self.procs
+    }
+    pub fn set_procs(&mut self, procs: Vec<Proc>) {
+        self.procs = procs;
+    }
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug.as_ref()
+    }
+    pub fn set_debug_info(&mut self, debug: Option<DebugInfo>) {
+        self.debug = debug;
+    }
+    pub fn location(&self, pc: usize) -> Option<&SourceLoc> {
+        self.debug.as_ref().and_then(|d| d.locations.get(pc))
+    }
+    pub fn pc(&self) -> usize {
+        self.ptr
+    }
+    pub fn stack(&self) -> &[Word] {
+        &self.stack[..self.stack_size]
+    }
+    pub fn stack_mut(&mut self) -> &mut [Word] {
+        &mut self.stack[..self.stack_size]
+    }
+    // Replaces the stack, e.g. with an earlier `stack().to_vec()`.
+    pub fn set_stack(&mut self, values: &[Word]) {
+        self.stack[..values.len()].copy_from_slice(values);
+        self.stack_size = values.len();
+    }
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
     }
 }
 
 #[derive(Debug)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Instruction {
     
     PUSH(Word),
@@ -455,20 +1028,514 @@ pub enum Instruction {
     NEQ,
     JNZ(Word),
     HALT,
-    BLIND
+    BLIND,
+    LOADB,
+    LOADW
 }
 
 
     
 
+impl Instruction {
+    // The 72 digits `get_byte_code` writes for this instruction.
+    pub fn encode(&self) -> String {
+        inst_to_string(*self)
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::PUSH(_) => "push",
+            Instruction::ADD => "add",
+            Instruction::SUB => "sub",
+            Instruction::MUL => "mul",
+            Instruction::DIV => "div",
+            Instruction::DUP(_) => "dup",
+            Instruction::DUMP => "dump",
+            Instruction::PRINT => "print",
+            Instruction::JMP(_) => "jmp",
+            Instruction::EQ => "eq",
+            Instruction::NEQ => "neq",
+            Instruction::JNZ(_) => "jnz",
+            Instruction::HALT => "halt",
+            Instruction::BLIND => "blind",
+            Instruction::LOADB => "loadb",
+            Instruction::LOADW => "loadw"
+        }
+    }
+    pub fn operand(&self) -> Option<Word> {
+        match self {
+            Instruction::PUSH(o)
+            | Instruction::DUP(o)
+            | Instruction::JMP(o)
+            | Instruction::JNZ(o) => Some(*o),
+            _ => None
+        }
+    }
+    // The same instruction with another operand, if it has one.
+    pub fn with_operand(self, value: Word) -> Instruction {
+        match self {
+            Instruction::PUSH(_) => Instruction::PUSH(value),
+            Instruction::DUP(_) => Instruction::DUP(value),
+            Instruction::JMP(_) => Instruction::JMP(value),
+            Instruction::JNZ(_) => Instruction::JNZ(value),
+            inst => inst
+        }
+    }
+    pub fn to_json(&self) -> Json {
+        match self.operand() {
+            Some(o) => Json::object(vec![("op", Json::str(self.mnemonic())), ("operand", Json::from(o))]),
+            None => Json::object(vec![("op", Json::str(self.mnemonic()))])
+        }
+    }
+}
+
+// Prints the instruction the way it is written in a .vm source file.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.operand() {
+            Some(o) => write!(f, "{} {}", self.mnemonic(), o),
+            None => write!(f, "{}", self.mnemonic())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunOutcome {
+    Halted(Word),
+    Finished,
+    Error(RuntimeError)
+}
+
+impl RunOutcome {
+    pub fn to_json(&self) -> Json {
+        match self {
+            RunOutcome::Halted(code) => Json::object(vec![("kind", Json::str("halted")), ("code", Json::from(*code))]),
+            RunOutcome::Finished => Json::object(vec![("kind", Json::str("finished"))]),
+            RunOutcome::Error(error) => Json::object(vec![
+                ("kind", Json::str("error")),
+                ("error", error.to_json())
+            ])
+        }
+    }
+    // A process exit status is a byte, so larger `halt` codes saturate at
+    // 255 instead of wrapping around to a misleading 0.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunOutcome::Halted(code) => (*code).min(255) as i32,
+            RunOutcome::Finished => 0,
+            RunOutcome::Error(_) => MEXT as i32
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepStatus {
+    Running,
+    Halted,
+    Error(RuntimeError)
+}
+
 #[derive(Debug)]
 pub enum InstSuccess {
     OK
 }
-#[derive(Debug)]
+// What went wrong, without the surrounding VM state; see `RuntimeError`.
+#[derive(Debug, Clone, PartialEq)]
 pub enum InstError {
-    StackOverflow(String),
-    StackUnderflow(String),
-    DivByZero(String),
-    IllegalMemAccess(String)
+    StackOverflow { capacity: usize },
+    StackUnderflow { needed: usize, found: usize },
+    DivByZero,
+    IllegalMemAccess { target: Word, program_len: usize },
+    DataOutOfBounds { address: Word, width: usize, data_len: usize }
+}
+
+impl InstError {
+    // Stable identifier used in JSON reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InstError::StackOverflow { .. } => "stack_overflow",
+            InstError::StackUnderflow { .. } => "stack_underflow",
+            InstError::DivByZero => "division_by_zero",
+            InstError::IllegalMemAccess { .. } => "illegal_memory_access",
+            InstError::DataOutOfBounds { .. } => "data_out_of_bounds"
+        }
+    }
+}
+
+impl std::fmt::Display for InstError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstError::StackOverflow { capacity } => write!(f, "stack overflow: the stack holds at most {} values", capacity),
+            InstError::StackUnderflow { needed, found } => write!(f, "stack underflow: needs {} value{} but the stack has {}", needed, if *needed == 1 { "" } else { "s" }, found),
+            InstError::DivByZero => write!(f, "division by zero"),
+            InstError::IllegalMemAccess { target, program_len } => write!(f, "illegal memory access: jump to {} outside a program of {} instructions", target, program_len),
+            InstError::DataOutOfBounds { address, width, data_len } => write!(f, "data out of bounds: reading {} byte{} at {} from {} bytes of data", width, if *width == 1 { "" } else { "s" }, address, data_len)
+        }
+    }
+}
+
+impl std::error::Error for InstError {}
+
+// A failed instruction together with the state the VM was in when it failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub stack: Vec<Word>,
+    pub location: Option<SourceLoc>,
+    pub kind: InstError
+}
+
+impl RuntimeError {
+    // Multi-line report for terminals: the error, where it happened and the
+    // top of the stack at that point.
+    pub fn report(&self) -> String {
+        let mut text = format!("error: {}\n", self.kind);
+        if let Some(loc) = &self.location {
+            text += &format!("  --> {}\n", loc);
+        }
+        text += &format!("   | pc {}: {}\n", self.pc, self.instruction);
+        let shown: Vec<String> = self.stack.iter().rev().take(8).map(|w| w.to_string()).collect();
+        let more = if self.stack.len() > 8 { format!(", ... {} more", self.stack.len() - 8) } else { String::new() };
+        text += &format!("   | stack (top first): [{}{}]\n", shown.join(", "), more);
+        text
+    }
+    pub fn to_json(&self) -> Json {
+        let mut fields = vec![
+            ("kind", Json::str(self.kind.code())),
+            ("message", Json::Str(self.kind.to_string())),
+            ("pc", Json::from(self.pc)),
+            ("instruction", self.instruction.to_json()),
+            ("stack", Json::Array(self.stack.iter().map(|w| Json::from(*w)).collect()))
+        ];
+        if let Some(loc) = &self.location {
+            fields.push(("location", Json::Str(loc.to_string())));
+        }
+        Json::object(fields)
+    }
+}
+
+// One line: "foo.vm:12:3: division by zero" when the source is known,
+// otherwise the pc and instruction stand in for it.
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(loc) => write!(f, "{}: {}", loc, self.kind),
+            None => write!(f, "instruction {} ({}): {}", self.pc, self.instruction, self.kind)
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // A VM with `source` loaded and its output discarded.
+    fn vm(source: &str) -> VM {
+        let assembly = assemble(source).unwrap();
+        let mut vm = VM::init();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.load_program(assembly.program);
+        vm.set_data(assembly.data);
+        vm
+    }
+
+    // `snapshot` with one line replaced and the checksum recomputed.
+    fn forged(snapshot: &str, field: &str, value: &str) -> String {
+        let body: String = snapshot.lines()
+            .filter(|l| !l.starts_with("checksum "))
+            .map(|l| match l.split_once(' ') {
+                Some((name, _rest)) if name == field => format!("{} {}\n", field, value),
+                _ => format!("{}\n", l)
+            })
+            .collect();
+        format!("{}checksum {:016x}\n", body, checksum(body.as_bytes()))
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut vm = vm(".data\nbytes: .bytes 1, 2, 3\n.text\npush 7\npush 8\nadd\npush bytes\nloadb\nhalt\n");
+        for _ in 0..3 {
+            vm.step();
+        }
+        let restored = VM::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.stack(), &[15]);
+        assert_eq!(restored.pc(), 3);
+        assert_eq!(restored.program(), vm.program());
+        assert_eq!(restored.data(), &[1, 2, 3]);
+        assert_eq!(restored.snapshot(), vm.snapshot());
+        vm.run_program();
+        let mut restored = VM::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.exit_code(), Some(1));
+        assert_eq!(restored.stack(), &[15]);
+        assert_eq!(restored.step(), StepStatus::Halted);
+    }
+
+    #[test]
+    fn snapshots_keep_procedures_and_debug_info() {
+        let source = ".proc f ( a -- b )\n    push 0\n    div\n.endp\npush 1\npush 2\njmp f\n";
+        let assembly = assemble(source).unwrap();
+        let mut vm = vm(source);
+        vm.set_procs(assembly.procs.clone());
+        vm.set_debug_info(Some(assembly.debug_info("f.vm")));
+        vm.ptr = 3;
+        let mut restored = VM::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.procs(), assembly.procs.as_slice());
+        assert_eq!(restored.debug_info(), vm.debug_info());
+        for _ in 0..3 {
+            restored.step();
+        }
+        match restored.step() {
+            StepStatus::Error(e) => assert_eq!(e.location.map(|l| l.to_string()), Some("f.vm:3:5".to_string())),
+            status => panic!("{:?}", status)
+        }
+    }
+
+    #[test]
+    fn tampered_snapshots_are_rejected() {
+        let snapshot = vm("push 1\npush 2\n").snapshot();
+        let message = |text: &str| VM::restore(text).unwrap_err().to_string();
+        assert_eq!(message(&snapshot.replace("stack_size 0", "stack_size 1")), "Invalid snapshot: checksum mismatch");
+        assert_eq!(message(&snapshot.replace("checksum ", "checksum x")), "Invalid snapshot: malformed checksum");
+        assert_eq!(message(&snapshot[..snapshot.find("checksum").unwrap()]), "Invalid snapshot: missing checksum");
+        assert_eq!(message(&forged(&snapshot, "stack_size", "1")), "Invalid snapshot: stack_size does not match stack contents");
+        assert_eq!(message(&forged(&snapshot, "ptr", "3")), "Invalid snapshot: ptr out of program bounds");
+        assert_eq!(message(&forged(&snapshot, "LVMSNAP", "9")), "Invalid snapshot: unsupported version 9");
+        assert_eq!(message(&forged(&snapshot, "data", "abc")), "Invalid data section");
+        assert_eq!(message(&forged(&format!("{}extra 1\n", snapshot), "data", "")), "Invalid snapshot: unexpected line `extra 1`");
+    }
+
+    #[test]
+    fn malformed_programs_are_rejected() {
+        let snapshot = vm("push 1\n").snapshot();
+        let message = |program: &str| VM::restore(&forged(&snapshot, "program", program)).unwrap_err().to_string();
+        assert_eq!(message(&"9".repeat(72)), "Invalid snapshot: Invalid character `9` in bytecode");
+        assert_eq!(message(&"0".repeat(71)), "Invalid snapshot: Bytecode of 71 digits does not split into 72 digit instructions");
+        let unknown = format!("{:08b}{}", 99, "0".repeat(64));
+        assert_eq!(message(&format!("{}{}", "0".repeat(72), unknown)), "Invalid snapshot: Unknown opcode 99 in instruction 1");
+        // The same checks apply to bytecode files.
+        assert_eq!(VM::init().decode(&unknown).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        let mut vm = VM::init();
+        vm.decode(&format!("{}{}", "0".repeat(71), "1")).unwrap();
+        assert_eq!(vm.program(), &[Instruction::PUSH(1)]);
+    }
+
+    #[test]
+    fn step_back_undoes_pushes_and_pops() {
+        let mut vm = vm("push 1\npush 2\nadd\npush 9\ndup 1\nhalt\n");
+        vm.record_history(16);
+        while vm.step() == StepStatus::Running {}
+        assert_eq!((vm.stack(), vm.exit_code()), (&[3, 9][..], Some(3)));
+        // HALT took the 3 off the stack; undoing it puts it back and clears
+        // the exit code.
+        assert!(vm.step_back());
+        assert_eq!((vm.stack(), vm.pc(), vm.exit_code()), (&[3, 9, 3][..], 5, None));
+        assert!(vm.step_back());
+        assert_eq!((vm.stack(), vm.pc()), (&[3, 9][..], 4));
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        // `add` popped 1 and 2 and wrote 3 over the 1.
+        assert_eq!((vm.stack(), vm.pc()), (&[1, 2][..], 2));
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!((vm.stack(), vm.pc()), (&[][..], 0));
+        assert!(!vm.step_back());
+        // Replaying gives the same run.
+        assert_eq!(vm.run_program(), RunOutcome::Halted(3));
+    }
+
+    #[test]
+    fn step_back_restores_overwritten_slots() {
+        // There is no instruction that writes the data segment, so only
+        // stack slots need restoring: the 5 popped by `add` is overwritten
+        // by the next push.
+        let mut vm = vm(".data\nb: .bytes 42\n.text\npush 5\npush 6\nadd\npush b\nloadb\n");
+        vm.record_history(16);
+        for _ in 0..5 {
+            vm.step();
+        }
+        assert_eq!(vm.stack(), &[11, 42]);
+        for _ in 0..3 {
+            assert!(vm.step_back());
+        }
+        assert_eq!(vm.stack(), &[5, 6]);
+        assert_eq!(vm.data(), &[42]);
+        vm.stack_mut()[1] = 7;
+        vm.step();
+        assert_eq!(vm.stack(), &[12]);
+    }
+
+    #[test]
+    fn history_keeps_the_newest_records() {
+        let mut vm = vm("push 1\npush 2\npush 3\npush 4\n");
+        vm.record_history(2);
+        for _ in 0..4 {
+            vm.step();
+        }
+        assert_eq!(vm.history_len(), 2);
+        assert!(vm.step_back() && vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!((vm.stack(), vm.pc()), (&[1, 2][..], 2));
+        // Lowering the cap drops the oldest records, 0 turns recording off.
+        for _ in 0..2 {
+            vm.step();
+        }
+        vm.record_history(1);
+        assert_eq!(vm.history_len(), 1);
+        vm.record_history(0);
+        assert_eq!(vm.history_len(), 0);
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn failed_steps_leave_the_history_alone() {
+        let mut vm = vm("push 1\npush 2\npush 0\ndiv\n");
+        vm.record_history(3);
+        for _ in 0..3 {
+            vm.step();
+        }
+        assert!(matches!(vm.step(), StepStatus::Error(_)));
+        assert_eq!(vm.history_len(), 3);
+        assert!(vm.step_back());
+        assert_eq!((vm.stack(), vm.pc()), (&[1, 2][..], 2));
+        assert!(vm.step_back() && vm.step_back());
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn stepping_pauses_and_resumes() {
+        let mut vm = vm("push 1\npush 2\nadd\nprint\nhalt\n");
+        assert_eq!(vm.step(), StepStatus::Running);
+        assert_eq!((vm.pc(), vm.stack()), (1, &[1][..]));
+        assert_eq!(vm.run_for(0), StepStatus::Running);
+        assert_eq!(vm.pc(), 1);
+        assert_eq!(vm.run_for(2), StepStatus::Running);
+        assert_eq!((vm.pc(), vm.stack()), (3, &[3][..]));
+        assert_eq!(vm.run_for(10), StepStatus::Halted);
+        assert_eq!(vm.exit_code(), Some(3));
+        // A halted VM stays halted.
+        assert_eq!(vm.step(), StepStatus::Halted);
+        assert_eq!(vm.run_for(1), StepStatus::Halted);
+        assert_eq!(vm.run_for(0), StepStatus::Halted);
+    }
+
+    #[test]
+    fn run_until_stops_at_the_pc_or_the_end() {
+        let mut counter = vm("push 3\nloop:\npush 1\nsub\ndup 0\npush 0\nneq\njnz loop\n");
+        assert_eq!(counter.run_until(1), StepStatus::Running);
+        // Steps at least once even when it starts at `pc`.
+        assert_eq!(counter.run_until(1), StepStatus::Running);
+        assert_eq!((counter.pc(), counter.stack()), (1, &[2][..]));
+        assert_eq!(counter.run_until(1), StepStatus::Running);
+        assert_eq!(counter.stack(), &[1]);
+        // Never reached again: runs off the end.
+        assert_eq!(counter.run_until(1), StepStatus::Halted);
+        assert_eq!((counter.pc(), counter.exit_code()), (7, None));
+        let mut failing = vm("push 1\npush 0\ndiv\npush 2\n");
+        match failing.run_until(3) {
+            StepStatus::Error(e) => assert_eq!((e.pc, e.kind), (2, InstError::DivByZero)),
+            status => panic!("{:?}", status)
+        }
+        assert_eq!(failing.pc(), 2);
+    }
+
+    #[test]
+    fn run_outcomes_and_exit_codes() {
+        let halted = vm("push 3\nhalt\npush 4\n").run_program();
+        assert_eq!(halted, RunOutcome::Halted(3));
+        assert_eq!((halted.exit_code(), halted.to_json().to_string()), (3, "{\"kind\":\"halted\",\"code\":3}".to_string()));
+        let finished = vm("push 3\n").run_program();
+        assert_eq!(finished, RunOutcome::Finished);
+        assert_eq!((finished.exit_code(), finished.to_json().to_string()), (0, "{\"kind\":\"finished\"}".to_string()));
+        assert_eq!(vm("").run_program(), RunOutcome::Finished);
+        let failed = vm("add\n").run_program();
+        assert_eq!(failed.exit_code(), 202);
+        assert_eq!(failed.to_json().get("kind"), Some(&Json::str("error")));
+        // Exit statuses are a byte; larger codes saturate.
+        assert_eq!(vm("push 255\nhalt\n").run_program().exit_code(), 255);
+        assert_eq!(vm("push 256\nhalt\n").run_program().exit_code(), 255);
+        assert_eq!(vm("push 4294967296\nhalt\n").run_program().exit_code(), 255);
+        assert_eq!(vm("push -1\nhalt\n").run_program(), RunOutcome::Halted(Word::MAX));
+        assert_eq!(RunOutcome::Halted(Word::MAX).to_json().to_string(), "{\"kind\":\"halted\",\"code\":18446744073709551615}");
+    }
+
+    // The error `source` fails with, with debug info if `file` is given.
+    fn failure(source: &str, file: Option<&str>) -> RuntimeError {
+        let mut vm = vm(source);
+        vm.set_debug_info(file.map(|f| assemble(source).unwrap().debug_info(f)));
+        match vm.run_program() {
+            RunOutcome::Error(e) => e,
+            outcome => panic!("{:?}", outcome)
+        }
+    }
+
+    #[test]
+    fn runtime_error_reports() {
+        let source = "push 7\npush 0\n  div\n";
+        let plain = failure(source, None);
+        assert_eq!(plain.to_string(), "instruction 2 (div): division by zero");
+        assert_eq!(plain.report(), "error: division by zero\n   | pc 2: div\n   | stack (top first): [0, 7]\n");
+        assert_eq!(plain.to_json().to_string(), "{\"kind\":\"division_by_zero\",\"message\":\"division by zero\",\"pc\":2,\"instruction\":{\"op\":\"div\"},\"stack\":[7,0]}");
+        let located = failure(source, Some("e.vm"));
+        assert_eq!(located.to_string(), "e.vm:3:3: division by zero");
+        assert_eq!(located.report(), "error: division by zero\n  --> e.vm:3:3\n   | pc 2: div\n   | stack (top first): [0, 7]\n");
+        assert_eq!(located.to_json().to_string(), "{\"kind\":\"division_by_zero\",\"message\":\"division by zero\",\"pc\":2,\"instruction\":{\"op\":\"div\"},\"stack\":[7,0],\"location\":\"e.vm:3:3\"}");
+    }
+
+    #[test]
+    fn runtime_error_kinds() {
+        let report = |source: &str| failure(source, None).report();
+        assert_eq!(report("push 1\nadd\n"), "error: stack underflow: needs 2 values but the stack has 1\n   | pc 1: add\n   | stack (top first): [1]\n");
+        assert_eq!(report("dup 0\n"), "error: stack underflow: needs 1 value but the stack has 0\n   | pc 0: dup 0\n   | stack (top first): []\n");
+        assert_eq!(failure("jmp 5\n", None).to_string(), "instruction 0 (jmp 5): illegal memory access: jump to 5 outside a program of 1 instructions");
+        assert_eq!(failure("push 3\nloadw\n", None).to_json().get("kind"), Some(&Json::str("data_out_of_bounds")));
+        assert_eq!(failure("push 3\nloadb\n", None).kind.to_string(), "data out of bounds: reading 1 byte at 3 from 0 bytes of data");
+        let overflow = failure("top:\npush 1\njmp top\n", None);
+        assert_eq!(overflow.kind, InstError::StackOverflow { capacity: STACK_CAP - 1 });
+        // Only the top 8 values are shown.
+        assert!(overflow.report().ends_with("   | stack (top first): [1, 1, 1, 1, 1, 1, 1, 1, ... 2039 more]\n"));
+        assert_eq!(overflow.to_json().get("stack").and_then(|s| s.as_array()).map(|s| s.len()), Some(STACK_CAP - 1));
+    }
+
+    #[test]
+    fn debug_sections_round_trip() {
+        let assembly = assemble("start: push 1\n  jmp start\n").unwrap();
+        let mut debug = assembly.debug_info("dir with spaces/a.vm");
+        debug.locations[1].file = "lib.vm".to_string();
+        assert_eq!(debug.encode(), ".debug\nloc 0 1 8 dir with spaces/a.vm\nloc 1 2 3 lib.vm\nlabel 0 start\n");
+        assert_eq!(DebugInfo::decode(&debug.encode()[".debug\n".len()..]).unwrap(), debug);
+        let mut vm = VM::init();
+        vm.load_program(assembly.program.clone());
+        vm.set_debug_info(Some(debug.clone()));
+        let mut loaded = VM::init();
+        loaded.decode(&vm.encode()).unwrap();
+        assert_eq!((loaded.program(), loaded.debug_info()), (&assembly.program[..], Some(&debug)));
+        assert_eq!(loaded.location(1).map(|l| l.to_string()), Some("lib.vm:2:3".to_string()));
+        assert_eq!(debug.label("start"), Some(0));
+        assert_eq!(debug.labels_at(0), ["start"]);
+        // Without a section there is no debug info.
+        let mut plain = VM::init();
+        plain.decode(&assembly.program.iter().map(|i| i.encode()).collect::<String>()).unwrap();
+        assert_eq!(plain.debug_info(), None);
+    }
+
+    #[test]
+    fn bad_debug_sections_are_rejected() {
+        let error = |section: &str| DebugInfo::decode(section).unwrap_err().to_string();
+        assert_eq!(error("loc 1 1 1 a.vm\n"), "Invalid debug entry `loc 1 1 1 a.vm`");
+        assert_eq!(error("loc 0 x 1 a.vm\n"), "Invalid debug entry `loc 0 x 1 a.vm`");
+        assert_eq!(error("loc 0 1 1\n"), "Invalid debug entry `loc 0 1 1`");
+        assert_eq!(error("label x start\n"), "Invalid debug entry `label x start`");
+        assert_eq!(error("line 0 1\n"), "Invalid debug entry `line 0 1`");
+        assert_eq!(DebugInfo::decode("\nloc 0 1 1 a.vm\n\n").unwrap().locations.len(), 1);
+        let program = Instruction::HALT.encode();
+        let error = VM::init().decode(&format!("{}\n.debug\nloc 0 1\n", program)).unwrap_err();
+        assert_eq!((error.kind(), error.to_string()), (std::io::ErrorKind::InvalidData, "Invalid debug entry `loc 0 1`".to_string()));
+    }
 }